@@ -1,60 +1,213 @@
 use chrono::prelude::*;
-use rusqlite::{params, Connection};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use regex::Regex;
+use rusqlite::{params, Connection, OpenFlags, ToSql};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
 use std::fs;
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime};
 use structopt::StructOpt;
-use toml;
 
 // TODO:
 // Remove unwrap and handle errors
 // Support more output formats. Write without completely reading into memory.
 // Better init (creating profiles directory, config etc.)
 // Refactor code for readability
-// Handle paths better, find format! alternate
 // Alert about UTF-8 filename assumption
 
 struct Context {
   profiles: Vec<Profile>,
   working_directory: PathBuf,
+  max_entries_per_file: Option<usize>,
+  on_profile_error: ErrorPolicy,
+  profile_retries: u32,
+  verify_output: bool,
+  filter: HistoryFilter,
+  output_mode: OutputMode,
+  summary_top_domains: usize,
+  textfile_path: Option<PathBuf>,
+  emit_stats: bool,
+  export_keywords: bool,
+  export_input_history: bool,
+  pool_size: u32,
+  query: HistoryQuery,
+  immutable: bool,
+  // Set by --snapshots-dir, profiles are existing snapshots to read in place
+  offline: bool,
+  diff_mode: bool,
+  retain_exports: usize,
+  post_export_command: Option<String>,
+  post_export_strict: bool,
+  export: ExportOptions,
+  merge_profiles: bool,
+  merge_dedupe: bool,
+  latest_per_url: bool,
+  // Export and save the cursor one page_size page at a time
+  checkpoint_pages: bool,
+  runs_log_size: usize,
+  // Named pipe or socket the history is streamed into instead of export files
+  output: Option<StreamOutput>,
+  on_output_error: OutputErrorPolicy,
+  filename_timestamp_format: Option<String>,
+  duplicate_filenames: DuplicateFilenames,
+  strict_snapshot_freshness: bool,
+  date_partitioned: bool,
+  one_file_per_entry: bool,
+  gzip_log: bool,
+  snapshot_retries: u32,
+  // Set by --explain
+  explain: bool,
+  // Set by --stdout
+  stdout: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 struct State {
   last_run: u64,
   last_sync: u64,
   last_historyvisit_id: u64,
+  // Every run that read the profile, unlike last_sync which only moves when
+  // something was exported. 0 means the profile was never checked
+  #[serde(default)]
+  last_check: u64,
+  // Most recent runs first, capped at runs_log_size
+  #[serde(default)]
+  runs: Vec<RunRecord>,
+  // Entries exported by every saved run, for firefox_history_exported_total
+  #[serde(default)]
+  exported_total: u64,
+  // The same per visit type name, for firefox_history_visits_by_type_total
+  #[serde(default)]
+  exported_by_type: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RunRecord {
+  timestamp: u64,
+  count: usize,
+  from_id: u64,
+  to_id: u64,
+  duration_ms: u64,
 }
 
 impl State {
-  fn from_json(filename: &str) -> Self {
-    if !std::path::Path::new(filename).exists() {
-      return Self {
-        last_run: 0,
-        last_sync: 0,
-        last_historyvisit_id: 0,
-      };
+  // Falls back to the .bak of the previous write when the file doesn't parse,
+  // and exits when neither does rather than start over from cursor 0
+  fn from_json(filename: &Path) -> Self {
+    if !filename.exists() {
+      return Self::default();
     }
 
-    let file = fs::File::open(filename).unwrap();
-    let reader = BufReader::new(file);
+    match Self::parse(filename) {
+      Ok(state) => state,
+      Err(err) => {
+        let backup = Self::backup_path(filename);
+        Log::warn(format!(
+          "Unreadable state \"{}\" ({}), using \"{}\"",
+          filename.to_string_lossy(),
+          err,
+          backup.to_string_lossy()
+        ))
+        .emit();
+        match Self::parse(&backup) {
+          Ok(state) => state,
+          Err(err) => {
+            Log::error(format!(
+              "Unreadable state backup \"{}\" ({}), fix or remove \"{}\" to start over",
+              backup.to_string_lossy(),
+              err,
+              filename.to_string_lossy()
+            ))
+            .emit();
+            std::process::exit(1);
+          }
+        }
+      }
+    }
+  }
 
-    let u: serde_json::Value = serde_json::from_reader(reader).unwrap();
-    return serde_json::from_value(u.clone()).unwrap();
+  fn parse(filename: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    let reader = BufReader::new(fs::File::open(filename)?);
+    Ok(serde_json::from_reader(reader)?)
   }
 
-  fn to_json(&self, filename: &str) {
-    let file = fs::OpenOptions::new()
-      .create(true)
-      .write(true)
-      .open(filename)
-      .unwrap();
-    let writer = BufWriter::new(file);
-    let state = serde_json::to_value(&self).unwrap();
-    serde_json::to_writer_pretty(writer, &state).unwrap();
+  fn backup_path(filename: &Path) -> PathBuf {
+    filename.with_extension("json.bak")
+  }
+
+  // Keeps the previous state as .bak and replaces the file atomically, so a
+  // crash mid-write never leaves a truncated state.json behind
+  fn to_json(&self, filename: &Path, pretty: bool) {
+    // A corrupt state.json would overwrite the good .bak it was loaded from
+    if Self::parse(filename).is_ok() {
+      fs::copy(filename, Self::backup_path(filename)).unwrap();
+    }
+    let temp_path = filename.with_extension("json.tmp");
+    let mut writer = create_file(&temp_path);
+    write_json(self, &mut writer, pretty);
+    writer.into_inner().unwrap().sync_all().unwrap();
+    fs::rename(&temp_path, filename).unwrap();
+  }
+
+  // Adds exported entries of these visit types to the cumulative counters
+  fn count_exported(&mut self, visit_types: &[u8]) {
+    self.exported_total += visit_types.len() as u64;
+    for &visit_type in visit_types {
+      *self.exported_by_type.entry(visit_type_name(visit_type).to_string()).or_default() += 1;
+    }
+  }
+
+  fn record_run(&mut self, run: RunRecord, cap: usize) {
+    self.runs.insert(0, run);
+    self.runs.truncate(cap);
+  }
+}
+
+// Where a profile's state is kept between runs
+trait StateStore: std::fmt::Debug {
+  fn load(&self) -> State;
+  fn save(&mut self, state: &State);
+}
+
+// state.json in the profile's directory
+#[derive(Debug)]
+struct FileStateStore {
+  path: PathBuf,
+  pretty: bool,
+}
+
+impl StateStore for FileStateStore {
+  fn load(&self) -> State {
+    State::from_json(&self.path)
+  }
+
+  fn save(&mut self, state: &State) {
+    state.to_json(&self.path, self.pretty);
+  }
+}
+
+// Keeps the state for the life of the process only, used by --no-state
+#[derive(Debug, Default)]
+struct MemoryStateStore {
+  state: Option<State>,
+}
+
+impl StateStore for MemoryStateStore {
+  fn load(&self) -> State {
+    self.state.clone().unwrap_or_default()
+  }
+
+  fn save(&mut self, state: &State) {
+    self.state = Some(state.clone());
   }
 }
 
@@ -62,8 +215,18 @@ impl State {
 struct Profile {
   name: String,
   path: PathBuf,
+  // Per-profile directory under working_directory holding the snapshot,
+  // state and exports
+  directory: PathBuf,
+  // Name of the history database, both in the Firefox profile and snapshot
+  database_filename: String,
   db_path: PathBuf,
+  pool: Pool<SqliteConnectionManager>,
+  store: Box<dyn StateStore>,
   state: State,
+  // Set when backup_places couldn't take a snapshot, the profile is left out
+  // of the run rather than exported from its old snapshot
+  stalled: bool,
 }
 
 #[derive(Debug)]
@@ -80,171 +243,5246 @@ struct MozHistoryVisits {
   visit_type: u8,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Bumped whenever HistoryEntry changes in a way consumers must know about
+const EXPORT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct HistoryEntry {
+  // moz_historyvisits id, or moz_places id with source = "places", used to
+  // advance the cursor
+  #[serde(default)]
+  id: u64,
+  // Source profile, only set in merged exports and with tag_profile
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  profile: Option<String>,
+  // Machine the export ran on, to tell devices apart in a shared archive
+  #[serde(default)]
+  device: String,
+  #[serde(default)]
+  os: String,
+  #[serde(default)]
+  visit_type: u8,
+  #[serde(default)]
+  visit_type_name: String,
   date: String,
+  // UTC offset of `date`, so consumers can rebuild the local time from
+  // visit_date alone
+  #[serde(default)]
+  tz_offset_seconds: i32,
+  // Left out of exports with export_titles = false
+  #[serde(default, skip_serializing_if = "titles_omitted")]
   title: String,
   url: String,
   visit_date: i64,
+  // Gap to the next visit of the profile in this run. An approximation of
+  // time on page that ignores idle time, None for the last visit
+  #[serde(default)]
+  dwell_ms: Option<i64>,
+  // Visits of the url over the whole history, only set with latest_per_url
+  // and source = "places"
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  visit_count: Option<u64>,
+  // Only set with source = "places"
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  frecency: Option<i64>,
+  // Set when url was cut at max_url_length
+  #[serde(default, skip_serializing_if = "is_false")]
+  url_truncated: bool,
 }
 
-#[derive(Debug, StructOpt)]
-#[structopt(name = "Firefox export", about = "Export Firefox data to files")]
-struct Opt {
-  #[structopt(short = "c", long = "config")]
-  config: PathBuf,
+#[derive(Debug, Serialize)]
+struct TopSite {
+  url: String,
+  #[serde(skip_serializing_if = "titles_omitted")]
+  title: String,
+  frecency: i64,
+  visit_count: i64,
+  last_visit_date: Option<i64>,
 }
 
-impl Context {
-  fn from_config(filename: PathBuf) -> Context {
-    let raw_config: String = fs::read_to_string(filename).unwrap();
-    let value = raw_config.parse::<toml::Value>().unwrap();
+#[derive(Debug, Serialize)]
+struct DomainCount {
+  domain: String,
+  count: usize,
+}
 
-    let working_directory = PathBuf::from(value["working_directory"].as_str().unwrap());
+#[derive(Debug, Serialize)]
+struct DailySummary {
+  date: String,
+  total_visits: usize,
+  unique_urls: usize,
+  top_domains: Vec<DomainCount>,
+}
 
-    let mut context = Context {
-      working_directory: working_directory.clone(),
-      profiles: vec![],
+#[derive(Debug, Serialize)]
+struct ProfileStats {
+  total_places: i64,
+  total_visits: i64,
+  oldest_visit_date: Option<i64>,
+  newest_visit_date: Option<i64>,
+  db_size_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KeywordEntry {
+  keyword: String,
+  url: String,
+  post_data: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct InputHistoryEntry {
+  input: String,
+  url: String,
+  // Decays over time in Firefox, so it is fractional
+  use_count: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+  working_directory: PathBuf,
+  max_entries_per_file: Option<usize>,
+  #[serde(default)]
+  on_profile_error: ErrorPolicy,
+  #[serde(default = "default_profile_retries")]
+  profile_retries: u32,
+  #[serde(default)]
+  verify_output: bool,
+  #[serde(default)]
+  redact_url_patterns: Vec<String>,
+  #[serde(default)]
+  redact_action: RedactAction,
+  // Characters kept of longer urls and titles, no limit when unset
+  max_url_length: Option<usize>,
+  max_title_length: Option<usize>,
+  // Page titles can carry as much as the url, e.g. search terms
+  #[serde(default = "default_export_titles")]
+  export_titles: bool,
+  // moz_historyvisits.visit_type values to leave out, e.g. 5 and 6 for
+  // redirects
+  #[serde(default)]
+  exclude_visit_types: Vec<u8>,
+  // Url schemes to export, or ["all"]
+  #[serde(default = "default_schemes")]
+  schemes: Vec<String>,
+  #[serde(default)]
+  output_mode: OutputMode,
+  #[serde(default = "default_summary_top_domains")]
+  summary_top_domains: usize,
+  // node_exporter textfile collector output
+  textfile_path: Option<PathBuf>,
+  #[serde(default)]
+  log_format: LogFormat,
+  #[serde(default)]
+  timezone: Timezone,
+  #[serde(default)]
+  timestamp_unit: TimestampUnit,
+  #[serde(default)]
+  on_bad_timestamp: BadTimestamp,
+  #[serde(default)]
+  on_missing_place: MissingPlace,
+  // The cursor follows moz_places.id with "places"
+  #[serde(default)]
+  source: Source,
+  // Export at most this many visits per profile and run, leaving the rest
+  // for the next run or --catch-up pass
+  chunk_visits: Option<usize>,
+  #[serde(default)]
+  order_by: OrderBy,
+  // Device name on each entry, the hostname when unset
+  device_label: Option<String>,
+  // Indent exports; state.json stays indented by default as it is small and
+  // edited by hand
+  #[serde(default)]
+  pretty: bool,
+  #[serde(default)]
+  compression: Compression,
+  // File holding a hex encoded Ed25519 secret key to sign exports with
+  signing_key: Option<PathBuf>,
+  #[serde(default = "default_pretty_state")]
+  pretty_state: bool,
+  // Named pipe, or unix socket with a unix: prefix, to write NDJSON into
+  // rather than history_export files
+  output: Option<String>,
+  #[serde(default)]
+  on_output_error: OutputErrorPolicy,
+  // strftime pattern for the timestamp in export file names, epoch
+  // milliseconds when unset
+  filename_timestamp_format: Option<String>,
+  #[serde(default)]
+  duplicate_filenames: DuplicateFilenames,
+  // Extra snapshot attempts when one fails PRAGMA integrity_check. 0 skips
+  // the check
+  #[serde(default)]
+  snapshot_retries: u32,
+  // Append visits to history.ndjson.gz, one gzip member per line
+  #[serde(default)]
+  gzip_log: bool,
+  // Write every visit to its own <visit id>.json in the profile directory
+  #[serde(default)]
+  one_file_per_entry: bool,
+  // Write history exports to <YYYY>/<MM>/<DD>/<profile>-<timestamp>.json
+  #[serde(default)]
+  date_partitioned: bool,
+  // Fail rather than warn when a snapshot is older than the last run
+  #[serde(default)]
+  strict_snapshot_freshness: bool,
+  // Nest history exports under this key instead of writing a bare array
+  json_root_key: Option<String>,
+  // Recent runs kept in state.json, 0 keeps none
+  #[serde(default = "default_runs_log_size")]
+  runs_log_size: usize,
+  #[serde(default)]
+  emit_stats: bool,
+  // Custom search keywords from moz_keywords
+  #[serde(default)]
+  export_keywords: bool,
+  // Typed address bar input and the place picked for it, from moz_inputhistory
+  #[serde(default)]
+  export_input_history: bool,
+  #[serde(default = "default_database_filename")]
+  database_filename: String,
+  // Maximum open connections per profile snapshot
+  #[serde(default = "default_pool_size")]
+  pool_size: u32,
+  // Longest wait for a database to open, for sources on stalled mounts
+  #[serde(default = "default_open_timeout_ms")]
+  open_timeout_ms: u64,
+  // Visits read per query. On its own this only bounds each statement,
+  // checkpoint_pages bounds what a run holds in memory
+  #[serde(default = "default_page_size")]
+  page_size: u32,
+  // Export every page as it is read and save the cursor after it, rather
+  // than holding the whole run
+  #[serde(default)]
+  checkpoint_pages: bool,
+  // Places looked up per query, SQLite allows at most 999 parameters
+  #[serde(default = "default_place_batch_size")]
+  place_batch_size: usize,
+  // Open places.sqlite as an immutable read-only URI
+  #[serde(default)]
+  immutable: bool,
+  // Export the visits added and deleted since the previous snapshot
+  #[serde(default)]
+  diff_mode: bool,
+  // Keep only this many history exports per profile, 0 keeps all
+  #[serde(default)]
+  retain_exports: usize,
+  // Shell command run after each profile's export
+  post_export_command: Option<String>,
+  // Fail the run when the hook exits non-zero
+  #[serde(default)]
+  post_export_strict: bool,
+  // Write a single merged_history export covering all profiles
+  #[serde(default)]
+  merge_profiles: bool,
+  #[serde(default)]
+  merge_dedupe: bool,
+  // Set `profile` on every entry, not just in merged exports
+  #[serde(default)]
+  tag_profile: bool,
+  // Export one entry per url with its latest visit, over the whole history
+  #[serde(default)]
+  latest_per_url: bool,
+  #[serde(default)]
+  profile: BTreeMap<String, ProfileConfig>,
+  // Names of the [profile.<name>] tables in the order they were written
+  #[serde(skip)]
+  profile_order: Vec<String>,
+  // Newline separated profile names, only these profiles are exported
+  profiles_file: Option<PathBuf>,
+  // Same as [profile.<name>] tables, easier for tools to append to
+  #[serde(default)]
+  profiles: Vec<NamedProfileConfig>,
+}
+
+fn default_profile_retries() -> u32 {
+  3
+}
+
+// What to do when reading a profile's snapshot fails
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum ErrorPolicy {
+  // Log the error and leave the profile's state untouched
+  Skip,
+  // Abort the whole run
+  #[default]
+  Fail,
+  // Try again up to profile_retries times, then skip
+  Retry,
+}
+
+type ReadResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+impl ErrorPolicy {
+  // Runs `read` against the profile, returning None if the profile should be
+  // skipped for this run.
+  fn apply<T>(
+    self,
+    retries: u32,
+    profile: &Profile,
+    read: impl Fn(&Profile) -> ReadResult<T>,
+  ) -> Option<T> {
+    let attempts = match self {
+      ErrorPolicy::Retry => retries.max(1),
+      _ => 1,
     };
 
-    for profile in value["profile"].as_table().iter() {
-      for (profile_name, path) in profile.iter() {
-        context.profiles.push(Profile {
-          name: profile_name.to_string(),
-          path: PathBuf::from(path["firefox_path"].as_str().unwrap()),
-          db_path: PathBuf::from(format!(
-            "{}/profiles/{}/places.sqlite",
-            &working_directory.to_string_lossy(),
-            profile_name
-          )),
-          state: State::from_json(
-            format!(
-              "{}/profiles/{}/state.json",
-              &working_directory.to_string_lossy(),
-              profile_name
-            )
-            .as_str(),
-          ),
-        });
+    for attempt in 1..=attempts {
+      match read(profile) {
+        Ok(value) => return Some(value),
+        Err(err) => {
+          Log::error(format!(
+            "Failed to read profile \"{}\" (attempt {}/{}): {}",
+            profile.name, attempt, attempts, err
+          ))
+          .profile(&profile.name)
+          .emit();
+          if attempt < attempts {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+          }
+        }
       }
     }
 
-    return context;
-  }
-
-  fn backup_places(&self) {
-    for profile in &self.profiles {
-      fs::copy(format!("{}/places.sqlite", profile.path.to_string_lossy()), &profile.db_path).unwrap();
+    if self == ErrorPolicy::Fail {
+      std::process::exit(1);
     }
+    Log::warn(format!("Skipping profile \"{}\"", profile.name))
+      .profile(&profile.name)
+      .emit();
+    None
   }
 }
 
-impl Profile {
-  fn get_place_entry(&self, place_id: u32) -> MozPlaces {
-    let conn = Connection::open(&self.db_path).unwrap();
-    let mut stmt = conn
-      .prepare("SELECT url, title FROM moz_places where id = :place_id")
-      .unwrap();
-    let place_iter = stmt
-      .query_map(params![&place_id], |row| Ok(MozPlaces { url: row.get(0)?, title: row.get(1).unwrap_or("".to_string()) }))
-      .unwrap();
+fn default_place_batch_size() -> usize {
+  500
+}
 
-    return place_iter.take(1).next().unwrap().unwrap();
-  }
+fn default_page_size() -> u32 {
+  10000
+}
 
-  fn get_history(&self, from_id: u64) -> Vec<HistoryEntry> {
-    let mut history_entries: Vec<HistoryEntry> = vec![];
+fn default_export_titles() -> bool {
+  true
+}
 
-    let conn = Connection::open(&self.db_path).unwrap();
-    let mut stmt = conn
-      .prepare(
-        "SELECT id, place_id, visit_date, visit_type FROM moz_historyvisits where id > :from_id",
-      )
-      .unwrap();
-    let history_iter = stmt
-      .query_map(params![&(from_id as i64)], |row| {
-        Ok(MozHistoryVisits {
-          id: row.get(0)?,
-          place_id: row.get(1)?,
-          visit_date: row.get(2)?,
-          visit_type: row.get(3)?,
+fn default_runs_log_size() -> usize {
+  50
+}
+
+fn default_pool_size() -> u32 {
+  4
+}
+
+fn default_open_timeout_ms() -> u64 {
+  5000
+}
+
+fn default_database_filename() -> String {
+  "places.sqlite".to_string()
+}
+
+fn default_pretty_state() -> bool {
+  true
+}
+
+fn default_schemes() -> Vec<String> {
+  vec!["http".to_string(), "https".to_string()]
+}
+
+fn default_summary_top_domains() -> usize {
+  10
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum OutputMode {
+  // Every visit as a HistoryEntry
+  #[default]
+  History,
+  // One DailySummary per day instead of the raw visits
+  Summary,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum RedactAction {
+  // Leave matching entries out of the export
+  #[default]
+  Drop,
+  // Replace the matched part of the url with ***
+  Mask,
+}
+
+#[derive(Debug, Clone)]
+struct Redaction {
+  patterns: Vec<Regex>,
+  action: RedactAction,
+}
+
+impl Redaction {
+  fn new(patterns: &[String], action: RedactAction) -> Self {
+    let patterns = patterns
+      .iter()
+      .map(|pattern| {
+        Regex::new(pattern).unwrap_or_else(|err| {
+          Log::error(format!("Invalid redact_url_patterns entry \"{}\": {}", pattern, err)).emit();
+          std::process::exit(1);
         })
       })
-      .unwrap();
+      .collect();
 
-    for visit in history_iter {
-      let entry = visit.unwrap();
-      history_entries.push(HistoryEntry {
-        url: self.get_place_entry(entry.place_id).url,
-        title: self.get_place_entry(entry.place_id).title,
-        visit_date: entry.visit_date,
-        date: Local
-          .timestamp(
-            // check why timestamp_nanos result in wrong datetime
-            Local.timestamp_millis(entry.visit_date / 1000).timestamp(),
-            0,
-          )
-          .to_string(),
+    Redaction { patterns, action }
+  }
+
+  fn apply(&self, history: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+    if self.patterns.is_empty() {
+      return history;
+    }
+
+    history
+      .into_iter()
+      .filter_map(|mut entry| {
+        entry.url = self.redact(entry.url)?;
+        Some(entry)
       })
+      .collect()
+  }
+
+  // `url` as exported, or None when it is dropped
+  fn redact(&self, mut url: String) -> Option<String> {
+    let matched = self.patterns.iter().any(|p| p.is_match(&url));
+    match self.action {
+      RedactAction::Drop if matched => None,
+      RedactAction::Mask if matched => {
+        for pattern in &self.patterns {
+          url = pattern.replace_all(&url, "***").into_owned();
+        }
+        Some(url)
+      }
+      _ => Some(url),
     }
+  }
+}
+
+// Visit type of a link typed into the address bar
+const VISIT_TYPE_TYPED: u8 = 2;
 
-    return history_entries;
+// Name of a moz_historyvisits visit_type, after Firefox's TRANSITION_*
+// constants
+fn visit_type_name(visit_type: u8) -> &'static str {
+  match visit_type {
+    1 => "link",
+    VISIT_TYPE_TYPED => "typed",
+    3 => "bookmark",
+    4 => "embed",
+    5 => "redirect_permanent",
+    6 => "redirect_temporary",
+    7 => "download",
+    8 => "framed_link",
+    9 => "reload",
+    _ => "unknown",
   }
 }
 
-fn write_history_to_file(history: &Vec<HistoryEntry>, filename: &str) {
-  let file = fs::OpenOptions::new()
-    .create(true)
-    .write(true)
-    .open(filename)
-    .unwrap();
-  let writer = BufWriter::new(file);
-  let state = serde_json::to_value(history).unwrap();
-  serde_json::to_writer_pretty(writer, &state).unwrap();
+// How visits are read from a snapshot
+#[derive(Debug, Clone)]
+struct HistoryQuery {
+  page_size: u32,
+  place_batch_size: usize,
+  missing_place: MissingPlace,
+  source: Source,
+  // Most visits a run reads per profile, set by chunk_visits
+  chunk_visits: Option<usize>,
+  order_by: OrderBy,
+  // Case-insensitive substring of the url or title, set by --search
+  search: Option<String>,
+  // Applied to everything read, before any filter sees the urls
+  redaction: Redaction,
 }
 
-fn main() {
-  let opt = Opt::from_args();
-  let mut context = Context::from_config(opt.config);
+// Visits get_history read past a cursor. `read` and `read_to` include the
+// visits redaction dropped, so the cursor still moves past them
+struct ReadHistory {
+  entries: Vec<HistoryEntry>,
+  read: usize,
+  read_to: u64,
+}
 
-  context.backup_places();
-  for profile in &mut context.profiles {
-    println!("Getting history entries for profile \"{}\"", profile.name);
-
-    let now = SystemTime::now()
-      .duration_since(SystemTime::UNIX_EPOCH)
-      .unwrap()
-      .as_millis();
-    profile.state.last_run = now as u64;
-    let history = profile.get_history(profile.state.last_historyvisit_id);
-
-    if history.len() > 0 {
-      write_history_to_file(
-        &history,
-        format!(
-          "{}/profiles/{}/history_export_{}.json",
-          &context.working_directory.to_string_lossy(),
-          profile.name,
-          now
-        )
-        .as_str(),
-      );
-      println!("Exported {} entries!", history.len());
+impl HistoryQuery {
+  // Condition for `search`, bound as :search
+  fn search_clause(&self) -> &'static str {
+    match (&self.search, self.source) {
+      (None, _) => "",
+      (Some(_), Source::Visits) => {
+        " AND place_id IN (SELECT id FROM moz_places \
+         WHERE url LIKE :search ESCAPE '\\' OR title LIKE :search ESCAPE '\\')"
+      }
+      (Some(_), Source::Places) => " AND (url LIKE :search ESCAPE '\\' OR title LIKE :search ESCAPE '\\')",
+    }
+  }
+
+  // Table whose ids the cursor follows
+  fn table(&self) -> &'static str {
+    match self.source {
+      Source::Visits => "moz_historyvisits",
+      Source::Places => "moz_places",
+    }
+  }
+
+  // LIKE pattern for `search`, with its wildcards escaped
+  fn search_pattern(&self) -> Option<String> {
+    self.search.as_ref().map(|term| {
+      let term = term.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+      format!("%{}%", term)
+    })
+  }
+}
+
+// Everything deciding which of the read visits end up in an export, and the
+// device they are tagged with
+#[derive(Debug)]
+struct HistoryFilter {
+  device: String,
+  // Lowercase url schemes to keep, None keeps all
+  schemes: Option<Vec<String>>,
+  exclude_visit_types: Vec<u8>,
+  // Set by --typed-only
+  only_visit_types: Option<Vec<u8>>,
+  // Set by --search-regex, matched against the url or title
+  search_regex: Option<Regex>,
+  max_url_length: Option<usize>,
+  max_title_length: Option<usize>,
+  tag_profile: bool,
+  // Set by --sample
+  sample: Option<Sample>,
+  on_bad_timestamp: BadTimestamp,
+}
+
+// Keeps about `fraction` of the visits. Whether a visit is kept only depends
+// on its id and the seed, so the same seed always picks the same visits.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+  fraction: f64,
+  seed: u64,
+}
+
+impl Sample {
+  fn keeps(&self, id: u64) -> bool {
+    // splitmix64 of the id mixed with the seed, as a fraction of u64::MAX
+    let mut z = self.seed ^ id.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z as f64 / u64::MAX as f64) < self.fraction
+  }
+}
+
+// What to do with visits whose visit_date is zero or negative
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum BadTimestamp {
+  // Drop them, logging how many
+  #[default]
+  Skip,
+  // Export them dated at the epoch
+  ZeroEpoch,
+  // Export visit_date as read
+  KeepRaw,
+}
+
+// Table the export walks
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Source {
+  // One entry per moz_historyvisits row
+  #[default]
+  Visits,
+  // One entry per moz_places row, visited or not, without per-visit detail
+  Places,
+}
+
+// Order of the entries in an export. The cursor always follows the highest
+// id read, whatever the order.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum OrderBy {
+  // As read, which is by id
+  #[default]
+  Id,
+  VisitDateAsc,
+  VisitDateDesc,
+}
+
+impl OrderBy {
+  fn sort(self, history: &mut [HistoryEntry]) {
+    match self {
+      OrderBy::Id => {}
+      OrderBy::VisitDateAsc => history.sort_by_key(|entry| (entry.visit_date, entry.id)),
+      OrderBy::VisitDateDesc => history.sort_by_key(|entry| std::cmp::Reverse((entry.visit_date, entry.id))),
+    }
+  }
+}
+
+// What to do with visits whose place_id has no moz_places row, e.g. after
+// Firefox expired the place but not all of its visits
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum MissingPlace {
+  // Drop them, logging how many
+  #[default]
+  Skip,
+  // Export them with an empty url and title
+  Keep,
+  // Fail the profile's read
+  Fail,
+}
+
+impl HistoryFilter {
+  // Whether the scheme of `url` is one of `schemes`
+  fn keeps_scheme(&self, url: &str) -> bool {
+    let scheme = url.split(':').next().unwrap_or_default();
+    self
+      .schemes
+      .as_ref()
+      .map(|schemes| schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)))
+      .unwrap_or(true)
+  }
+
+  fn apply(&self, profile: &str, mut history: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+    match self.on_bad_timestamp {
+      BadTimestamp::Skip => {
+        let read = history.len();
+        history.retain(|entry| entry.visit_date > 0);
+        let dropped = read - history.len();
+        if dropped > 0 {
+          Log::warn(format!("Skipped {} entries with a visit_date of zero or less", dropped))
+            .profile(profile)
+            .count(dropped)
+            .emit();
+        }
+      }
+      BadTimestamp::ZeroEpoch => {
+        for entry in history.iter_mut().filter(|entry| entry.visit_date <= 0) {
+          entry.visit_date = 0;
+          entry.date = format_visit_date(0, None);
+          entry.tz_offset_seconds = visit_date_offset(0);
+        }
+      }
+      BadTimestamp::KeepRaw => {}
+    }
+    if self.schemes.is_some() {
+      let read = history.len();
+      history.retain(|entry| self.keeps_scheme(&entry.url));
+      let dropped = read - history.len();
+      if dropped > 0 {
+        Log::info(format!("Dropped {} entries with other url schemes", dropped))
+          .profile(profile)
+          .count(dropped)
+          .emit();
+      }
+    }
+    history.retain(|entry| {
+      !self.exclude_visit_types.contains(&entry.visit_type)
+        && self
+          .only_visit_types
+          .as_ref()
+          .map(|types| types.contains(&entry.visit_type))
+          .unwrap_or(true)
+        && self
+          .search_regex
+          .as_ref()
+          .map(|re| re.is_match(&entry.url) || re.is_match(&entry.title))
+          .unwrap_or(true)
+        && self.sample.map(|sample| sample.keeps(entry.id)).unwrap_or(true)
+    });
+    for entry in &mut history {
+      entry.device = self.device.clone();
+      entry.os = std::env::consts::OS.to_string();
+      if self.tag_profile {
+        entry.profile = Some(profile.to_string());
+      }
+    }
+    // Redaction already ran when reading, so a pattern can't miss what the
+    // cut removes
+    for entry in &mut history {
+      if let Some(url) = self.max_url_length.and_then(|max| truncate_chars(&entry.url, max)) {
+        entry.url = url;
+        entry.url_truncated = true;
+      }
+      if let Some(title) = self.max_title_length.and_then(|max| truncate_chars(&entry.title, max)) {
+        entry.title = title;
+      }
+    }
+    history
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileConfig {
+  // May be left out with auto_select to use the platform's profiles root
+  firefox_path: Option<PathBuf>,
+  #[serde(default)]
+  auto_select: bool,
+}
 
-      profile.state.last_historyvisit_id =
-        profile.state.last_historyvisit_id + (history.len() as u64);
-      profile.state.last_sync = now as u64;
+#[derive(Debug, Deserialize)]
+struct NamedProfileConfig {
+  name: String,
+  #[serde(flatten)]
+  config: ProfileConfig,
+}
+
+impl Config {
+  // Config files ending in .json are read as JSON, anything else as TOML.
+  // A filename of - reads TOML from stdin. "${NAME}" in any string value is
+  // replaced by the environment variable NAME, so secrets can stay out of
+  // the file, and "$${" is a literal "${".
+  fn from_file(filename: &Path) -> Self {
+    let raw_config: String = if filename == Path::new("-") {
+      let mut raw_config = String::new();
+      std::io::stdin().read_to_string(&mut raw_config).unwrap();
+      raw_config
+    } else {
+      fs::read_to_string(filename).unwrap()
+    };
+    let json = filename.extension().and_then(|e| e.to_str()) == Some("json");
+    let mut value: serde_json::Value = if json {
+      serde_json::from_str(&raw_config).unwrap()
     } else {
-      println!("Nothing to do!");
+      toml::from_str(&raw_config).unwrap()
+    };
+    if let Err(err) = substitute_env_vars(&mut value) {
+      Log::error(format!("Config {:?}: {}", filename, err)).emit();
+      std::process::exit(1);
     }
+    let mut config: Config = serde_json::from_value(value).unwrap();
+    // serde_json::Value keeps its keys sorted, so the order comes from the
+    // raw config
+    let order: ProfileOrder = if json {
+      serde_json::from_str(&raw_config).unwrap()
+    } else {
+      toml::from_str(&raw_config).unwrap()
+    };
+    config.profile_order = order.profile.0;
+    config
+  }
+}
 
-    profile.state.to_json(
-      format!(
-        "{}/profiles/{}/state.json",
-        &context.working_directory.to_string_lossy(),
-        profile.name
-      )
-      .as_str(),
+#[derive(Deserialize)]
+struct ProfileOrder {
+  #[serde(default)]
+  profile: KeyOrder,
+}
+
+// The keys of a table in document order, values ignored
+#[derive(Default)]
+struct KeyOrder(Vec<String>);
+
+impl<'de> Deserialize<'de> for KeyOrder {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    struct KeyVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for KeyVisitor {
+      type Value = KeyOrder;
+
+      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a table")
+      }
+
+      fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<KeyOrder, A::Error> {
+        let mut keys = vec![];
+        while let Some((key, _)) = map.next_entry::<String, serde::de::IgnoredAny>()? {
+          keys.push(key);
+        }
+        Ok(KeyOrder(keys))
+      }
+    }
+
+    deserializer.deserialize_map(KeyVisitor)
+  }
+}
+
+// Expands "${NAME}" in every string of `value`. An unset variable is an
+// error rather than an empty string.
+fn substitute_env_vars(value: &mut serde_json::Value) -> Result<(), String> {
+  match value {
+    serde_json::Value::String(string) => *string = expand_env(string)?,
+    serde_json::Value::Array(values) => {
+      for value in values {
+        substitute_env_vars(value)?;
+      }
+    }
+    serde_json::Value::Object(map) => {
+      for value in map.values_mut() {
+        substitute_env_vars(value)?;
+      }
+    }
+    _ => (),
+  }
+  Ok(())
+}
+
+// "$${" is left as a literal "${"
+fn expand_env(string: &str) -> Result<String, String> {
+  let mut expanded = String::new();
+  let mut rest = string;
+  while let Some(start) = rest.find("${") {
+    if rest[..start].ends_with('$') {
+      expanded.push_str(&rest[..start]);
+      expanded.push('{');
+      rest = &rest[start + 2..];
+      continue;
+    }
+    let end = match rest[start..].find('}') {
+      Some(end) => start + end,
+      None => break,
+    };
+    let name = &rest[start + 2..end];
+    let value = std::env::var(name)
+      .map_err(|_| format!("environment variable \"{}\" referenced as \"${{{}}}\" is not set", name, name))?;
+    expanded.push_str(&rest[..start]);
+    expanded.push_str(&value);
+    rest = &rest[end + 1..];
+  }
+  expanded.push_str(rest);
+  Ok(expanded)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum CompressionAlgo {
+  #[default]
+  None,
+  Gzip,
+  Zstd,
+}
+
+impl CompressionAlgo {
+  // Appended to the .json name of compressed exports
+  fn extension(self) -> &'static str {
+    match self {
+      CompressionAlgo::None => "",
+      CompressionAlgo::Gzip => ".gz",
+      CompressionAlgo::Zstd => ".zst",
+    }
+  }
+}
+
+// Compression of exports. state.json is never compressed.
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+struct Compression {
+  #[serde(default)]
+  algo: CompressionAlgo,
+  // 0-9 for gzip, 1-22 for zstd, the algorithm's default when unset
+  level: Option<i32>,
+}
+
+impl Compression {
+  // flate2 and zstd panic on levels outside these ranges
+  fn check_level(&self) -> Result<(), String> {
+    let (range, name) = match self.algo {
+      CompressionAlgo::None => return Ok(()),
+      CompressionAlgo::Gzip => (0..=9, "gzip"),
+      CompressionAlgo::Zstd => (1..=22, "zstd"),
+    };
+    match self.level {
+      Some(level) if !range.contains(&level) => Err(format!(
+        "compression level {} is out of range for {}, use {}-{}",
+        level,
+        name,
+        range.start(),
+        range.end()
+      )),
+      _ => Ok(()),
+    }
+  }
+}
+
+static LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+static TIMEZONE: OnceLock<Timezone> = OnceLock::new();
+// Set while stdout carries exported entries
+static LOGS_TO_STDERR: OnceLock<bool> = OnceLock::new();
+static TIMESTAMP_UNIT: OnceLock<TimestampUnit> = OnceLock::new();
+static OPEN_TIMEOUT_MS: OnceLock<u64> = OnceLock::new();
+static EXPORT_TITLES: OnceLock<bool> = OnceLock::new();
+
+fn open_timeout() -> std::time::Duration {
+  std::time::Duration::from_millis(OPEN_TIMEOUT_MS.get().copied().unwrap_or_else(default_open_timeout_ms))
+}
+
+// Runs `open` on a worker thread, giving up after open_timeout. A hung open
+// is left behind on its thread rather than blocking the run.
+fn with_open_timeout<T: Send + 'static>(
+  what: &Path,
+  open: impl FnOnce() -> rusqlite::Result<T> + Send + 'static,
+) -> Result<rusqlite::Result<T>, String> {
+  let (sender, receiver) = std::sync::mpsc::channel();
+  std::thread::spawn(move || {
+    let _ = sender.send(open());
+  });
+  receiver.recv_timeout(open_timeout()).map_err(|_| {
+    format!(
+      "opening \"{}\" timed out after {} ms",
+      what.to_string_lossy(),
+      open_timeout().as_millis()
     )
+  })
+}
+
+fn titles_omitted(_title: &String) -> bool {
+  !EXPORT_TITLES.get().copied().unwrap_or(true)
+}
+
+fn is_false(value: &bool) -> bool {
+  !value
+}
+
+// The first `max` characters of `text` followed by an ellipsis, None when it
+// already fits
+fn truncate_chars(text: &str, max: usize) -> Option<String> {
+  let (cut, _) = text.char_indices().nth(max)?;
+  Some(format!("{}…", &text[..cut]))
+}
+
+// Unit of visit_date in the source database
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum TimestampUnit {
+  // Microseconds, as Firefox stores them
+  #[default]
+  Us,
+  // Milliseconds, as written by some third-party tools
+  Ms,
+  // Guess per value, anything from 1e14 up is microseconds
+  Auto,
+}
+
+impl TimestampUnit {
+  // `visit_date` in microseconds
+  fn micros(self, visit_date: i64) -> i64 {
+    let ms = match self {
+      TimestampUnit::Us => false,
+      TimestampUnit::Ms => true,
+      TimestampUnit::Auto => visit_date.abs() < 100_000_000_000_000,
+    };
+    if ms {
+      visit_date * 1000
+    } else {
+      visit_date
+    }
+  }
+}
+
+// A visit_date in microseconds whatever the unit of the source
+fn visit_date_micros(visit_date: i64) -> i64 {
+  TIMESTAMP_UNIT.get().copied().unwrap_or_default().micros(visit_date)
+}
+
+// Zone used for the formatted date of each visit and summary days
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Timezone {
+  #[default]
+  Local,
+  // Skips the local zone lookup for every row
+  Utc,
+}
+
+// Formats a visit_date in microseconds with `format`, or chrono's default
+// display when None
+fn format_visit_date(visit_date: i64, format: Option<&str>) -> String {
+  format_visit_date_in(TIMEZONE.get().copied().unwrap_or_default(), visit_date, format)
+}
+
+fn format_visit_date_in(timezone: Timezone, visit_date: i64, format: Option<&str>) -> String {
+  fn render<Tz: TimeZone>(date: DateTime<Tz>, format: Option<&str>) -> String
+  where
+    Tz::Offset: std::fmt::Display,
+  {
+    match format {
+      Some(format) => date.format(format).to_string(),
+      None => date.to_string(),
+    }
+  }
+
+  let micros = visit_date_micros(visit_date);
+  match timezone {
+    Timezone::Local => render(Local.timestamp_opt(micros.div_euclid(1_000_000), 0).unwrap(), format),
+    Timezone::Utc => render(Utc.timestamp_micros(micros).unwrap(), format),
+  }
+}
+
+// Offset from UTC that format_visit_date renders `visit_date` with. Taken per
+// visit rather than per run, as local time changes offset with DST
+fn visit_date_offset(visit_date: i64) -> i32 {
+  let secs = visit_date_micros(visit_date).div_euclid(1_000_000);
+  match TIMEZONE.get().copied().unwrap_or_default() {
+    Timezone::Local => Local.timestamp_opt(secs, 0).unwrap().offset().fix().local_minus_utc(),
+    Timezone::Utc => 0,
+  }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+  #[default]
+  Text,
+  // One JSON object per line for log shippers
+  Json,
+}
+
+// A log line. Text logs print just the message, JSON logs also carry the
+// structured fields.
+#[derive(Debug, Serialize)]
+struct Log<'a> {
+  level: &'static str,
+  ts: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  profile: Option<&'a str>,
+  msg: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  count: Option<usize>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  duration_ms: Option<u128>,
+}
+
+impl<'a> Log<'a> {
+  fn new(level: &'static str, msg: impl Into<String>) -> Self {
+    Log {
+      level,
+      ts: Utc::now().to_rfc3339(),
+      profile: None,
+      msg: msg.into(),
+      count: None,
+      duration_ms: None,
+    }
+  }
+
+  fn info(msg: impl Into<String>) -> Self {
+    Log::new("info", msg)
+  }
+
+  fn warn(msg: impl Into<String>) -> Self {
+    Log::new("warn", msg)
+  }
+
+  fn error(msg: impl Into<String>) -> Self {
+    Log::new("error", msg)
+  }
+
+  fn profile(mut self, profile: &'a str) -> Self {
+    self.profile = Some(profile);
+    self
+  }
+
+  fn count(mut self, count: usize) -> Self {
+    self.count = Some(count);
+    self
+  }
+
+  fn duration(mut self, started: Instant) -> Self {
+    self.duration_ms = Some(started.elapsed().as_millis());
+    self
+  }
+
+  fn emit(self) {
+    let line = match LOG_FORMAT.get().copied().unwrap_or_default() {
+      LogFormat::Text => self.msg.clone(),
+      LogFormat::Json => serde_json::to_string(&self).unwrap(),
+    };
+
+    if self.level == "info" && !LOGS_TO_STDERR.get().copied().unwrap_or_default() {
+      println!("{}", line);
+    } else {
+      eprintln!("{}", line);
+    }
+  }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "Firefox export", about = "Export Firefox data to files")]
+struct Opt {
+  #[structopt(short = "c", long = "config")]
+  config: PathBuf,
+  /// Keep polling for new history entries and print them to stdout
+  #[structopt(long = "tail")]
+  tail: bool,
+  /// Print the new entries of all profiles to stdout as one NDJSON stream,
+  /// each tagged with its profile, instead of writing history exports.
+  /// Profiles follow each other in config order
+  #[structopt(long = "stdout")]
+  stdout: bool,
+  /// Seconds between polls in --tail mode
+  #[structopt(long = "tail-interval", default_value = "5")]
+  tail_interval: u64,
+  /// Persist the cursor to state.json with --tail, --since-file,
+  /// --typed-only, --search or --search-regex
+  #[structopt(long = "commit")]
+  commit: bool,
+  /// Only process these profiles (repeatable), overriding
+  /// FIREFOX_EXPORTER_PROFILES
+  #[structopt(long = "profile")]
+  profiles: Vec<String>,
+  /// Export entries newer than the last visit in this export file instead
+  /// of using state.json
+  #[structopt(long = "since-file")]
+  since_file: Option<PathBuf>,
+  /// Only export visits typed into the address bar
+  #[structopt(long = "typed-only")]
+  typed_only: bool,
+  /// Only export visits whose url or title contains this text, ignoring case
+  #[structopt(long = "search")]
+  search: Option<String>,
+  /// Only export visits whose url or title matches this regex
+  #[structopt(long = "search-regex")]
+  search_regex: Option<String>,
+  /// Only export this fraction (0.0-1.0) of the new visits. The cursor still
+  /// moves past the visits left out.
+  #[structopt(long = "sample")]
+  sample: Option<f64>,
+  /// Seed of --sample. Runs with the same seed keep the same visits, a
+  /// random seed is used when unset.
+  #[structopt(long = "sample-seed")]
+  sample_seed: Option<u64>,
+  /// Export the profiles listed in this profiles.ini instead of the
+  /// config's profile tables
+  #[structopt(long = "from-profile-ini")]
+  from_profile_ini: Option<PathBuf>,
+  /// Export every *.sqlite file in this directory as a profile named after
+  /// the file, reading them in place without a Firefox install
+  #[structopt(long = "snapshots-dir")]
+  snapshots_dir: Option<PathBuf>,
+  /// Ignore state.json, exporting the full history every run without
+  /// recording a cursor
+  #[structopt(long = "no-state")]
+  no_state: bool,
+  /// Overwrite export files that already exist instead of failing
+  #[structopt(long = "force")]
+  force: bool,
+  /// Log the history query, its parameters and EXPLAIN QUERY PLAN for each
+  /// profile before exporting
+  #[structopt(long = "explain")]
+  explain: bool,
+  /// With chunk_visits, keep exporting chunks until every profile is caught
+  /// up instead of stopping after one. Exports after the first chunk add the
+  /// cursor range to their timestamp
+  #[structopt(long = "catch-up")]
+  catch_up: bool,
+  /// Print each profile's cursor, the newest visit id in a fresh snapshot
+  /// and the visits in between, then exit without exporting
+  #[structopt(long = "preview-cursor")]
+  preview_cursor: bool,
+  #[structopt(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+  /// Re-export a range of visit ids from the existing snapshots without
+  /// copying the database or touching state.json
+  Replay {
+    #[structopt(long = "from-id")]
+    from_id: u64,
+    #[structopt(long = "to-id")]
+    to_id: u64,
+  },
+  /// Check that every profile's snapshot is readable and its last run is
+  /// recent, exiting non-zero otherwise
+  Health {
+    /// Maximum age of a profile's last run in seconds
+    #[structopt(long = "max-age", default_value = "86400")]
+    max_age: u64,
+  },
+  /// Print each profile's last run, last sync, cursor, recent run counts and
+  /// health verdict, without exporting
+  Status {
+    /// Maximum age of a profile's last run in seconds, as for health
+    #[structopt(long = "max-age", default_value = "86400")]
+    max_age: u64,
+    /// Print JSON instead of a table
+    #[structopt(long = "json")]
+    json: bool,
+  },
+  /// Compare two state.json files or two history exports
+  Diff {
+    #[structopt(parse(from_os_str))]
+    a: PathBuf,
+    #[structopt(parse(from_os_str))]
+    b: PathBuf,
+    /// List the differing entries
+    #[structopt(long = "verbose")]
+    verbose: bool,
+  },
+  /// Check an export against its .sig signature
+  Verify {
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+    /// File holding the hex encoded Ed25519 public key
+    #[structopt(long = "public-key")]
+    public_key: PathBuf,
+  },
+  /// Export each profile's highest frecency places from a fresh snapshot,
+  /// regardless of the cursor
+  TopSites {
+    #[structopt(long = "limit", default_value = "50")]
+    limit: usize,
+  },
+  /// Print how many visits each profile has past its cursor, without
+  /// exporting or touching state.json
+  Count {
+    /// Read the live Firefox database instead of taking a new snapshot
+    #[structopt(long = "no-backup")]
+    no_backup: bool,
+  },
+  /// Print the JSON Schema of history exports as this config writes them
+  PrintSchema,
+  /// Run SQLite's integrity and foreign key checks on each profile's
+  /// snapshot, exiting non-zero when one fails. Nothing is exported.
+  ValidateDb {
+    /// Only check these profiles (repeatable)
+    #[structopt(long = "profile")]
+    profiles: Vec<String>,
+  },
+}
+
+impl Context {
+  // `stateless` profiles start from an empty state that is never written
+  fn from_config(filename: PathBuf, stateless: bool, source: ProfileSource) -> Context {
+    let config = Config::from_file(&filename);
+    let _ = LOG_FORMAT.set(config.log_format);
+    let _ = TIMEZONE.set(config.timezone);
+    let _ = TIMESTAMP_UNIT.set(config.timestamp_unit);
+    let _ = OPEN_TIMEOUT_MS.set(config.open_timeout_ms.max(1));
+    let _ = EXPORT_TITLES.set(config.export_titles);
+    // Relative paths are resolved against the directory holding the config
+    // file rather than the current directory, which is / under cron. A
+    // config read from stdin resolves them against the current directory.
+    let config_directory = if filename == Path::new("-") {
+      Path::new("")
+    } else {
+      filename.parent().unwrap_or_else(|| Path::new(""))
+    };
+    let working_directory = config_directory.join(&config.working_directory);
+
+    let mut context = Context {
+      profiles: vec![],
+      working_directory: working_directory.clone(),
+      merge_profiles: config.merge_profiles,
+      merge_dedupe: config.merge_dedupe,
+      latest_per_url: config.latest_per_url,
+      checkpoint_pages: config.checkpoint_pages,
+      runs_log_size: config.runs_log_size,
+      explain: false,
+      strict_snapshot_freshness: config.strict_snapshot_freshness,
+      date_partitioned: config.date_partitioned,
+      one_file_per_entry: config.one_file_per_entry,
+      gzip_log: config.gzip_log,
+      snapshot_retries: config.snapshot_retries,
+      filename_timestamp_format: config.filename_timestamp_format.clone(),
+      duplicate_filenames: config.duplicate_filenames,
+      output: config.output.as_ref().map(|output| match output.strip_prefix("unix:") {
+        // Checked when connecting, the consumer may start after the exporter
+        Some(path) => StreamOutput::UnixSocket(config_directory.join(path)),
+        None => {
+          let path = config_directory.join(output);
+          if !is_fifo(&path) {
+            Log::error(format!("output \"{}\" is not a named pipe", path.to_string_lossy())).emit();
+            std::process::exit(1);
+          }
+          StreamOutput::Fifo(path)
+        }
+      }),
+      on_output_error: config.on_output_error,
+      stdout: false,
+      max_entries_per_file: config.max_entries_per_file,
+      on_profile_error: config.on_profile_error,
+      profile_retries: config.profile_retries,
+      verify_output: config.verify_output,
+      filter: HistoryFilter {
+        device: config.device_label.clone().unwrap_or_else(|| {
+          hostname::get()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+        }),
+        schemes: if config.schemes.iter().any(|s| s == "all") {
+          None
+        } else {
+          Some(config.schemes.iter().map(|s| s.to_lowercase()).collect())
+        },
+        exclude_visit_types: config.exclude_visit_types.clone(),
+        only_visit_types: None,
+        search_regex: None,
+        sample: None,
+        max_url_length: config.max_url_length,
+        max_title_length: config.max_title_length,
+        tag_profile: config.tag_profile,
+        on_bad_timestamp: config.on_bad_timestamp,
+      },
+      output_mode: config.output_mode,
+      summary_top_domains: config.summary_top_domains,
+      textfile_path: config.textfile_path.map(|path| config_directory.join(path)),
+      emit_stats: config.emit_stats,
+      export_keywords: config.export_keywords,
+      export_input_history: config.export_input_history,
+      pool_size: config.pool_size,
+      query: HistoryQuery {
+        page_size: config.page_size.max(1),
+        place_batch_size: config.place_batch_size.clamp(1, 999),
+        missing_place: config.on_missing_place,
+        source: config.source,
+        chunk_visits: config.chunk_visits.filter(|&n| n > 0),
+        order_by: config.order_by,
+        search: None,
+        redaction: Redaction::new(&config.redact_url_patterns, config.redact_action),
+      },
+      immutable: config.immutable,
+      offline: matches!(source, ProfileSource::SnapshotsDir(_)),
+      diff_mode: config.diff_mode,
+      retain_exports: config.retain_exports,
+      post_export_command: config.post_export_command,
+      post_export_strict: config.post_export_strict,
+      export: ExportOptions {
+        pretty: config.pretty,
+        force: false,
+        compression: config.compression,
+        signing_key: config
+          .signing_key
+          .as_ref()
+          .map(|path| read_signing_key(&config_directory.join(path))),
+        root_key: config.json_root_key.clone().filter(|key| !key.is_empty()),
+      },
+    };
+
+    if context.diff_mode && context.query.source == Source::Places {
+      Log::error("diff_mode compares visits and can't be combined with source = \"places\"").emit();
+      std::process::exit(1);
+    }
+    // Pruning only looks at history_export_* files directly in the profile
+    // directory
+    if context.date_partitioned && context.retain_exports > 0 {
+      Log::error("retain_exports can't be combined with date_partitioned").emit();
+      std::process::exit(1);
+    }
+    if let Err(err) = context.export.compression.check_level() {
+      Log::error(err).emit();
+      std::process::exit(1);
+    }
+    if context.checkpoint_pages {
+      let conflict = if context.query.chunk_visits.is_some() {
+        Some("chunk_visits, which stops a run after one chunk")
+      } else if context.merge_profiles {
+        Some("merge_profiles, which writes a single file")
+      } else if context.diff_mode {
+        Some("diff_mode, which compares whole snapshots")
+      } else if context.latest_per_url || context.output_mode == OutputMode::Summary {
+        Some("exports that cover the whole history")
+      } else {
+        None
+      };
+      if let Some(conflict) = conflict {
+        Log::error(format!("checkpoint_pages can't be combined with {}", conflict)).emit();
+        std::process::exit(1);
+      }
+    }
+    // An invalid spec would only fail once the snapshot is taken
+    if let Some(format) = &context.filename_timestamp_format {
+      if chrono::format::StrftimeItems::new(format).any(|item| item == chrono::format::Item::Error) {
+        Log::error(format!("filename_timestamp_format \"{}\" is not a valid strftime format", format)).emit();
+        std::process::exit(1);
+      }
+    }
+
+    // Name, directory and database file name of every profile
+    let mut sources: Vec<(String, PathBuf, String)> = vec![];
+    if let ProfileSource::ProfilesIni(ini) = source {
+      let database_filename = &config.database_filename;
+      sources = profiles_from_ini(ini, database_filename)
+        .into_iter()
+        .map(|(name, path)| (name, path, database_filename.clone()))
+        .collect();
+    } else if let ProfileSource::SnapshotsDir(snapshots) = source {
+      sources = snapshots_in(snapshots);
+    } else {
+      // Profiles run in the order they are written, [[profiles]] entries
+      // after the [profile.<name>] tables
+      let mut configured: Vec<(&String, &ProfileConfig)> = config.profile.iter().collect();
+      let order = &config.profile_order;
+      configured.sort_by_key(|(name, _)| order.iter().position(|n| n == *name));
+      for named in &config.profiles {
+        if configured.iter().any(|(name, _)| **name == named.name) {
+          Log::error(format!(
+            "Profile \"{}\" is defined more than once in [profile] and profiles",
+            named.name
+          ))
+          .emit();
+          std::process::exit(1);
+        }
+        configured.push((&named.name, &named.config));
+      }
+      for (profile_name, profile_config) in configured {
+        let mut firefox_path = match &profile_config.firefox_path {
+          Some(path) => config_directory.join(path),
+          None if profile_config.auto_select => match default_firefox_root() {
+            Some(root) => root,
+            None => {
+              Log::error(format!(
+                "Could not determine the Firefox profiles root for profile \"{}\", set firefox_path",
+                profile_name
+              ))
+              .profile(profile_name)
+              .emit();
+              std::process::exit(1);
+            }
+          },
+          None => {
+            Log::error(format!("firefox_path is required for profile \"{}\"", profile_name))
+              .profile(profile_name)
+              .emit();
+            std::process::exit(1);
+          }
+        };
+        if profile_config.auto_select {
+          firefox_path = match select_profile_directory(&firefox_path) {
+            Ok(path) => path,
+            Err(err) => {
+              Log::error(format!("Can't select a directory for profile \"{}\": {}", profile_name, err))
+                .profile(profile_name)
+                .emit();
+              std::process::exit(1);
+            }
+          };
+          Log::info(format!(
+            "Selected \"{}\" for profile \"{}\"",
+            firefox_path.to_string_lossy(),
+            profile_name
+          ))
+          .profile(profile_name)
+          .emit();
+        }
+        sources.push((profile_name.clone(), firefox_path, config.database_filename.clone()));
+      }
+    }
+
+    if let Some(path) = &config.profiles_file {
+      let path = config_directory.join(path);
+      let names = read_profiles_file(&path).unwrap_or_else(|err| {
+        Log::error(format!("Can't read profiles_file \"{}\": {}", path.to_string_lossy(), err)).emit();
+        std::process::exit(1);
+      });
+      for name in names.iter().filter(|name| !sources.iter().any(|(source, _, _)| source == *name)) {
+        Log::warn(format!("profiles_file lists unknown profile \"{}\"", name)).emit();
+      }
+      sources.retain(|(name, _, _)| names.contains(name));
+    }
+
+    for (profile_name, firefox_path, database_filename) in sources {
+      let profile_name = profile_name.as_str();
+      let directory = working_directory.join("profiles").join(profile_name);
+      // Profiles not named in the config can't have been set up beforehand
+      let created = if matches!(source, ProfileSource::Config) {
+        Ok(())
+      } else {
+        fs::create_dir_all(&directory).map_err(|e| e.to_string())
+      };
+      if let Err(err) = created.and_then(|_| check_writable(&directory)) {
+        Log::error(format!(
+          "working_directory is not writable, \"{}\": {}",
+          directory.to_string_lossy(),
+          err
+        ))
+        .profile(profile_name)
+        .emit();
+        std::process::exit(1);
+      }
+      // Offline snapshots are read in place rather than copied
+      let db_path = if context.offline {
+        firefox_path.join(&database_filename)
+      } else {
+        directory.join(&database_filename)
+      };
+      let store: Box<dyn StateStore> = if stateless {
+        Box::new(MemoryStateStore::default())
+      } else {
+        Box::new(FileStateStore {
+          path: directory.join("state.json"),
+          pretty: config.pretty_state,
+        })
+      };
+      context.profiles.push(Profile {
+        name: profile_name.to_string(),
+        path: firefox_path,
+        pool: build_pool(&db_path, config.pool_size),
+        db_path,
+        database_filename,
+        state: store.load(),
+        store,
+        directory,
+        stalled: false,
+      });
+    }
+
+    context
+  }
+
+  fn backup_places(&mut self) {
+    if self.offline {
+      return;
+    }
+    for profile in &mut self.profiles {
+      profile.stalled = false;
+      let source = profile.path.join(&profile.database_filename);
+      if let Err(err) = check_outside_profile(&profile.path, &profile.directory) {
+        Log::error(format!("Refusing to snapshot profile \"{}\": {}", profile.name, err))
+          .profile(&profile.name)
+          .emit();
+        std::process::exit(1);
+      }
+      // An immutable open ignores the -wal file, and so misses whatever
+      // Firefox has not checkpointed into places.sqlite yet
+      if self.immutable && has_pending_wal(&source) {
+        Log::warn(format!(
+          "Profile \"{}\" has uncheckpointed WAL data that immutable snapshots leave out",
+          profile.name
+        ))
+        .profile(&profile.name)
+        .emit();
+      }
+      // With snapshot_retries, a snapshot failing PRAGMA integrity_check is
+      // taken again rather than exported from
+      let attempts = self.snapshot_retries + 1;
+      for attempt in 1..=attempts {
+        if let Err(err) = take_snapshot(&profile.name, &source, &profile.db_path, self.immutable) {
+          Log::error(format!("Failed to snapshot profile \"{}\": {}", profile.name, err))
+            .profile(&profile.name)
+            .emit();
+          if self.on_profile_error == ErrorPolicy::Fail {
+            std::process::exit(1);
+          }
+          Log::warn(format!("Skipping profile \"{}\"", profile.name))
+            .profile(&profile.name)
+            .emit();
+          profile.stalled = true;
+          break;
+        }
+        // Pooled connections would still point at the replaced snapshot
+        profile.pool = build_pool(&profile.db_path, self.pool_size);
+        if self.snapshot_retries == 0 {
+          break;
+        }
+        match profile.open().map_err(|e| e.to_string()).and_then(|conn| integrity_check(&conn)) {
+          Ok(()) => break,
+          Err(err) => {
+            Log::warn(format!(
+              "Snapshot of profile \"{}\" failed the integrity check (attempt {}/{}): {}",
+              profile.name, attempt, attempts, err
+            ))
+            .profile(&profile.name)
+            .emit();
+            if attempt == attempts {
+              Log::error(format!("No consistent snapshot of profile \"{}\"", profile.name))
+                .profile(&profile.name)
+                .emit();
+              std::process::exit(1);
+            }
+          }
+        }
+      }
+    }
+  }
+
+  // A snapshot last written before the previous run means backup_places
+  // did not refresh it, and the run would re-read stale data. Offline
+  // snapshots are never refreshed, so they are not checked.
+  fn check_snapshot_freshness(&self) {
+    if self.offline {
+      return;
+    }
+    // Stalled profiles are skipped this run and have no new snapshot anyway
+    for profile in self.profiles.iter().filter(|p| !p.stalled) {
+      let mtime = SourceDb::of(&profile.db_path).map(|db| db.mtime).unwrap_or(0);
+      if mtime >= profile.state.last_run {
+        continue;
+      }
+      let message = format!(
+        "Snapshot of profile \"{}\" was modified at {} ms, before the last run at {} ms",
+        profile.name, mtime, profile.state.last_run
+      );
+      if self.strict_snapshot_freshness {
+        Log::error(message).profile(&profile.name).emit();
+        std::process::exit(1);
+      }
+      Log::warn(message).profile(&profile.name).emit();
+    }
+  }
+}
+
+// Copies the profile's database at `source` to `db_path`
+// Errors when the source doesn't open within open_timeout, as a copy would
+// hang just the same, or when the fallback copy fails too
+fn take_snapshot(profile: &str, source: &Path, db_path: &Path, immutable: bool) -> Result<(), String> {
+  let owned_source = source.to_path_buf();
+  let conn = with_open_timeout(source, move || open_source(&owned_source, immutable))?;
+  if let Err(err) = conn.and_then(|conn| vacuum_into(&conn, db_path)) {
+    Log::warn(format!(
+      "VACUUM INTO failed for profile \"{}\" ({}), copying instead",
+      profile, err
+    ))
+    .profile(profile)
+    .emit();
+    let copy = |from: &Path, to: &Path| {
+      fs::copy(from, to).map_err(|e| format!("copying \"{}\" failed: {}", from.to_string_lossy(), e))
+    };
+    copy(source, db_path)?;
+    // The copy is only complete with the visits still in the -wal file
+    if is_wal_mode(source) {
+      for suffix in ["-wal", "-shm"].iter() {
+        let sidecar = sidecar_path(source, suffix);
+        if sidecar.exists() {
+          copy(&sidecar, &sidecar_path(db_path, suffix))?;
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+// The first problem PRAGMA integrity_check finds, if any
+fn integrity_check(conn: &Connection) -> Result<(), String> {
+  let result: String = conn
+    .query_row("PRAGMA integrity_check(1)", params![], |row| row.get(0))
+    .map_err(|e| e.to_string())?;
+  if result == "ok" {
+    Ok(())
+  } else {
+    Err(result.lines().last().unwrap_or_default().to_string())
+  }
+}
+
+// One profile name per line. Blank lines and lines starting with # are
+// ignored.
+fn read_profiles_file(path: &Path) -> std::io::Result<Vec<String>> {
+  Ok(
+    fs::read_to_string(path)?
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .map(String::from)
+      .collect(),
+  )
+}
+
+// Snapshots, exports and state.json all go to the profile's directory, so
+// a read-only mount is caught here rather than by the first write
+fn check_writable(directory: &Path) -> Result<(), String> {
+  let probe = directory.join(".writetest");
+  fs::File::create(&probe).map_err(|e| e.to_string())?;
+  fs::remove_file(&probe).map_err(|e| e.to_string())
+}
+
+// Snapshots and exports written into the live Firefox profile could end up
+// copying places.sqlite onto itself, so `directory` must be elsewhere.
+fn check_outside_profile(firefox_path: &Path, directory: &Path) -> Result<(), String> {
+  let live = fs::canonicalize(firefox_path).map_err(|e| e.to_string())?;
+  let target = fs::canonicalize(directory).map_err(|e| e.to_string())?;
+  if target.starts_with(&live) {
+    return Err(format!(
+      "\"{}\" is inside the Firefox profile \"{}\"",
+      target.to_string_lossy(),
+      live.to_string_lossy()
+    ));
+  }
+  Ok(())
+}
+
+// Read connections to a snapshot. Connections are only opened on demand, and
+// fail rather than create an empty database when the snapshot is missing.
+fn build_pool(db_path: &Path, size: u32) -> Pool<SqliteConnectionManager> {
+  let manager = SqliteConnectionManager::file(db_path)
+    .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+    .with_init(|conn| conn.execute_batch("PRAGMA query_only = ON; PRAGMA busy_timeout = 5000;"));
+  Pool::builder()
+    .max_size(size.max(1))
+    .min_idle(Some(0))
+    .connection_timeout(open_timeout())
+    .build_unchecked(manager)
+}
+
+// Writes a compacted, consistent copy of the database open on `conn` to
+// `target`. Unlike a file copy this also picks up writes still in the WAL.
+fn vacuum_into(conn: &Connection, target: &Path) -> rusqlite::Result<()> {
+  // VACUUM INTO refuses to overwrite an existing database, and a -wal left
+  // by an earlier copied snapshot would be replayed onto the new one
+  for path in [target.to_path_buf(), sidecar_path(target, "-wal"), sidecar_path(target, "-shm")].iter() {
+    if path.exists() {
+      fs::remove_file(path).unwrap();
+    }
+  }
+  conn.execute("VACUUM INTO ?", params![target.to_string_lossy()])?;
+  Ok(())
+}
+
+// Read-only connection to a live Firefox database. An `immutable` source is
+// opened without locking or looking for a WAL, for backups and read-only
+// mounts that never change underneath us.
+fn open_source(source: &Path, immutable: bool) -> rusqlite::Result<Connection> {
+  if immutable {
+    let path = source
+      .to_string_lossy()
+      .replace('%', "%25")
+      .replace('?', "%3f")
+      .replace('#', "%23");
+    Connection::open_with_flags(
+      format!("file:{}?immutable=1&mode=ro", path),
+      OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+    )
+  } else {
+    Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_ONLY)
+  }
+}
+
+// places.sqlite-wal and places.sqlite-shm next to a database
+fn sidecar_path(db: &Path, suffix: &str) -> PathBuf {
+  let mut path = db.as_os_str().to_owned();
+  path.push(suffix);
+  PathBuf::from(path)
+}
+
+// Bytes 18 and 19 of the database header are 2 for WAL mode
+fn is_wal_mode(db: &Path) -> bool {
+  let mut header = [0u8; 20];
+  fs::File::open(db)
+    .and_then(|mut file| file.read_exact(&mut header))
+    .map(|_| header[18] == 2 && header[19] == 2)
+    .unwrap_or(false)
+}
+
+fn has_pending_wal(db: &Path) -> bool {
+  is_wal_mode(db)
+    && fs::metadata(sidecar_path(db, "-wal"))
+      .map(|m| m.len() > 0)
+      .unwrap_or(false)
+}
+
+// A [ProfileN] section of profiles.ini
+#[derive(Debug)]
+struct IniProfile {
+  name: String,
+  path: String,
+  is_relative: bool,
+  default: bool,
+}
+
+impl IniProfile {
+  // Relative paths are split on both separators, so a profiles.ini copied
+  // from Windows still resolves elsewhere
+  fn directory(&self, root: &Path) -> PathBuf {
+    if self.is_relative {
+      self
+        .path
+        .split(['/', '\\'])
+        .filter(|part| !part.is_empty())
+        .fold(root.to_path_buf(), |path, part| path.join(part))
+    } else {
+      PathBuf::from(&self.path)
+    }
+  }
+}
+
+// Parses profiles.ini, returning the profile sections and the default
+// profile path of the install section (Firefox 67+), if any.
+fn parse_profiles_ini(contents: &str) -> (Vec<IniProfile>, Option<String>) {
+  let mut profiles: Vec<IniProfile> = vec![];
+  let mut install_default: Option<String> = None;
+  let mut section = String::new();
+
+  for line in contents.lines().map(|l| l.trim()) {
+    if line.starts_with('[') && line.ends_with(']') {
+      section = line[1..line.len() - 1].to_string();
+      if section.starts_with("Profile") {
+        profiles.push(IniProfile {
+          name: section.clone(),
+          path: String::new(),
+          is_relative: true,
+          default: false,
+        });
+      }
+      continue;
+    }
+
+    let (key, value) = match line.find('=') {
+      Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+      None => continue,
+    };
+
+    if section.starts_with("Install") {
+      if key == "Default" {
+        install_default = Some(value.to_string());
+      }
+    } else if section.starts_with("Profile") {
+      let profile = profiles.last_mut().unwrap();
+      match key {
+        "Name" => profile.name = value.to_string(),
+        "Path" => profile.path = value.to_string(),
+        "IsRelative" => profile.is_relative = value == "1",
+        "Default" => profile.default = value == "1",
+        _ => {}
+      }
+    }
+  }
+
+  (profiles, install_default)
+}
+
+// Where the profiles of a run come from
+#[derive(Debug, Clone, Copy)]
+enum ProfileSource<'a> {
+  // The config's profile tables
+  Config,
+  // --from-profile-ini
+  ProfilesIni(&'a Path),
+  // --snapshots-dir
+  SnapshotsDir(&'a Path),
+}
+
+// Every *.sqlite file in `directory` as a profile named after the file, for
+// --snapshots-dir
+fn snapshots_in(directory: &Path) -> Vec<(String, PathBuf, String)> {
+  let entries = fs::read_dir(directory).unwrap_or_else(|err| {
+    Log::error(format!("Could not read \"{}\": {}", directory.to_string_lossy(), err)).emit();
+    std::process::exit(1);
+  });
+  let mut snapshots: Vec<(String, PathBuf, String)> = entries
+    .filter_map(|entry| entry.ok().map(|e| e.path()))
+    .filter(|path| path.is_file() && path.extension().map(|e| e == "sqlite").unwrap_or(false))
+    .map(|path| {
+      (
+        path.file_stem().unwrap().to_string_lossy().into_owned(),
+        directory.to_path_buf(),
+        path.file_name().unwrap().to_string_lossy().into_owned(),
+      )
+    })
+    .collect();
+  snapshots.sort();
+
+  if snapshots.is_empty() {
+    Log::error(format!("No .sqlite files in \"{}\"", directory.to_string_lossy())).emit();
+    std::process::exit(1);
+  }
+  snapshots
+}
+
+// Names and directories of the profiles listed in a profiles.ini, for
+// --from-profile-ini. Profiles without a `database_filename` are left out,
+// and it exits when none is left.
+fn profiles_from_ini(ini: &Path, database_filename: &str) -> Vec<(String, PathBuf)> {
+  let contents = fs::read_to_string(ini).unwrap_or_else(|err| {
+    Log::error(format!("Could not read \"{}\": {}", ini.to_string_lossy(), err)).emit();
+    std::process::exit(1);
+  });
+  let root = ini.parent().unwrap_or_else(|| Path::new(""));
+  let (profiles, _) = parse_profiles_ini(&contents);
+  let usable: Vec<(String, PathBuf)> = profiles
+    .iter()
+    .filter(|p| !p.path.is_empty() && p.directory(root).join(database_filename).is_file())
+    .map(|p| (p.name.clone(), p.directory(root)))
+    .collect();
+
+  if usable.is_empty() {
+    Log::error(format!("No usable profiles listed in \"{}\"", ini.to_string_lossy())).emit();
+    std::process::exit(1);
+  }
+  usable
+}
+
+// Where Firefox keeps profiles.ini on this platform
+#[cfg(windows)]
+fn default_firefox_root() -> Option<PathBuf> {
+  std::env::var_os("APPDATA").map(|appdata| PathBuf::from(appdata).join("Mozilla").join("Firefox"))
+}
+
+#[cfg(target_os = "macos")]
+fn default_firefox_root() -> Option<PathBuf> {
+  std::env::var_os("HOME").map(|home| {
+    PathBuf::from(home)
+      .join("Library")
+      .join("Application Support")
+      .join("Firefox")
+  })
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn default_firefox_root() -> Option<PathBuf> {
+  std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".mozilla").join("firefox"))
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+  use std::os::unix::fs::FileTypeExt;
+  fs::metadata(path).map(|m| m.file_type().is_fifo()).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+  false
+}
+
+fn modified_time(path: &Path) -> SystemTime {
+  fs::metadata(path)
+    .and_then(|m| m.modified())
+    .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+// Picks a profile directory from a Firefox profiles root. The default
+// profile listed in profiles.ini wins; otherwise the most recently modified
+// *.default* directory is used.
+fn select_profile_directory(root: &Path) -> Result<PathBuf, String> {
+  if let Ok(contents) = fs::read_to_string(root.join("profiles.ini")) {
+    let (profiles, install_default) = parse_profiles_ini(&contents);
+    let default_path = install_default
+      .and_then(|path| profiles.iter().find(|p| p.path == path))
+      .or_else(|| profiles.iter().find(|p| p.default))
+      .map(|p| p.directory(root));
+    if let Some(path) = default_path.filter(|p| p.is_dir()) {
+      return Ok(path);
+    }
+  }
+
+  // Windows keeps the profile directories in a Profiles subdirectory
+  let profiles_root = if root.join("Profiles").is_dir() {
+    root.join("Profiles")
+  } else {
+    root.to_path_buf()
+  };
+  let mut candidates: Vec<PathBuf> = fs::read_dir(&profiles_root)
+    .map_err(|e| format!("\"{}\": {}", profiles_root.to_string_lossy(), e))?
+    .filter_map(|entry| entry.ok().map(|e| e.path()))
+    .filter(|path| {
+      path.is_dir()
+        && path
+          .file_name()
+          .map(|n| n.to_string_lossy().contains(".default"))
+          .unwrap_or(false)
+    })
+    .collect();
+  candidates.sort_by_key(|path| modified_time(path));
+
+  candidates
+    .pop()
+    .ok_or_else(|| format!("no profile directory found in \"{}\"", root.to_string_lossy()))
+}
+
+impl Profile {
+  fn save_state(&mut self) {
+    self.store.save(&self.state);
+  }
+
+  // The next diff_mode run compares against this run's snapshot
+  fn keep_previous_snapshot(&self) {
+    fs::copy(&self.db_path, self.previous_db_path()).unwrap();
+  }
+
+  fn open(&self) -> Result<PooledConnection<SqliteConnectionManager>, r2d2::Error> {
+    self.pool.get()
+  }
+
+  // Places of the (visit id, place id) pairs by place id, looked up with one
+  // IN query per place_batch_size ids rather than one query per visit. Ids
+  // without a row are left out of the map, or mapped to an empty place, as
+  // on_missing_place says
+  fn get_place_entries(
+    conn: &Connection,
+    visits: impl IntoIterator<Item = (u32, u32)>,
+    query: &HistoryQuery,
+  ) -> rusqlite::Result<HashMap<u32, MozPlaces>> {
+    let visits: Vec<(u32, u32)> = visits.into_iter().collect();
+    let ids: Vec<u32> = visits.iter().map(|&(_, place_id)| place_id).collect::<BTreeSet<_>>().into_iter().collect();
+
+    let mut places = HashMap::with_capacity(ids.len());
+    for batch in ids.chunks(query.place_batch_size) {
+      let placeholders = vec!["?"; batch.len()].join(", ");
+      let mut stmt = conn.prepare(&format!(
+        "SELECT id, url, title FROM moz_places WHERE id IN ({})",
+        placeholders
+      ))?;
+      let rows = stmt.query_map(batch.iter().map(|id| id as &dyn ToSql), |row| {
+        Ok((
+          row.get::<_, u32>(0)?,
+          MozPlaces { url: row.get(1)?, title: row.get(2).unwrap_or("".to_string()) },
+        ))
+      })?;
+      for row in rows {
+        let (id, place) = row?;
+        places.insert(id, place);
+      }
+    }
+
+    let missing: Vec<u32> = ids.into_iter().filter(|id| !places.contains_key(id)).collect();
+    if !missing.is_empty() {
+      let action = match query.missing_place {
+        MissingPlace::Skip => "skipping",
+        MissingPlace::Keep => "keeping",
+        MissingPlace::Fail => "failing on",
+      };
+      Log::warn(format!("{} places missing from moz_places, {} their visits", missing.len(), action))
+        .count(missing.len())
+        .emit();
+      if query.missing_place == MissingPlace::Fail {
+        let (visit_id, place_id) = visits
+          .into_iter()
+          .filter(|(_, place_id)| !places.contains_key(place_id))
+          .min()
+          .unwrap_or_default();
+        return Err(rusqlite::Error::SqliteFailure(
+          rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_NOTFOUND),
+          Some(format!(
+            "visit {} points at place {}, which is missing from moz_places",
+            visit_id, place_id
+          )),
+        ));
+      }
+      if query.missing_place == MissingPlace::Keep {
+        for id in missing {
+          places.insert(id, MozPlaces { url: String::new(), title: String::new() });
+        }
+      }
+    }
+    Ok(places)
+  }
+
+  fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    let count: i64 = conn.query_row(
+      "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = :table",
+      params![table],
+      |row| row.get(0),
+    )?;
+    Ok(count > 0)
+  }
+
+  // Custom search keywords. Older schemas may not have moz_keywords at all.
+  fn get_keywords(conn: &Connection) -> rusqlite::Result<Vec<KeywordEntry>> {
+    if !Profile::table_exists(conn, "moz_keywords")? {
+      return Ok(vec![]);
+    }
+
+    let mut stmt = conn.prepare(
+      "SELECT k.keyword, p.url, k.post_data FROM moz_keywords k \
+         JOIN moz_places p ON p.id = k.place_id ORDER BY k.keyword",
+    )?;
+    let keyword_iter = stmt.query_map(params![], |row| {
+      Ok(KeywordEntry {
+        keyword: row.get(0)?,
+        url: row.get(1)?,
+        post_data: row.get(2)?,
+      })
+    })?;
+
+    keyword_iter.collect()
+  }
+
+  // What was typed in the address bar and the place it was completed to.
+  // Profiles that never used the address bar may lack moz_inputhistory.
+  fn get_input_history(conn: &Connection) -> rusqlite::Result<Vec<InputHistoryEntry>> {
+    if !Profile::table_exists(conn, "moz_inputhistory")? {
+      return Ok(vec![]);
+    }
+
+    let mut stmt = conn.prepare(
+      "SELECT i.input, p.url, i.use_count FROM moz_inputhistory i \
+         JOIN moz_places p ON p.id = i.place_id ORDER BY i.input, p.url",
+    )?;
+    let input_iter = stmt.query_map(params![], |row| {
+      Ok(InputHistoryEntry {
+        input: row.get(0)?,
+        url: row.get(1)?,
+        use_count: row.get::<_, Option<f64>>(2)?.unwrap_or_default(),
+      })
+    })?;
+
+    input_iter.collect()
+  }
+
+  // Redacted visits after the cursor `from_id`
+  fn get_history(conn: &Connection, from_id: u64, query: &HistoryQuery) -> rusqlite::Result<ReadHistory> {
+    let history = Profile::read_history_range(conn, from_id + 1, i64::MAX as u64, query, query.chunk_visits)?;
+    Ok(ReadHistory {
+      read: history.len(),
+      read_to: history.iter().map(|e| e.id).max().unwrap_or(from_id),
+      entries: query.redaction.apply(history),
+    })
+  }
+
+  // Redacted visits with ids between `from_id` and `to_id`, inclusive
+  fn get_history_range(
+    conn: &Connection,
+    from_id: u64,
+    to_id: u64,
+    query: &HistoryQuery,
+  ) -> rusqlite::Result<Vec<HistoryEntry>> {
+    Ok(query.redaction.apply(Profile::read_history_range(conn, from_id, to_id, query, None)?))
+  }
+
+  // Visits with ids between `from_id` and `to_id`, inclusive, or only the
+  // first `limit` of them
+  fn read_history_range(
+    conn: &Connection,
+    from_id: u64,
+    to_id: u64,
+    query: &HistoryQuery,
+    limit: Option<usize>,
+  ) -> rusqlite::Result<Vec<HistoryEntry>> {
+    if query.source == Source::Places {
+      // Never visited places have no last_visit_date and go through
+      // on_bad_timestamp with a visit_date of 0
+      return Profile::read_pages(conn, from_id, to_id, query, limit, |row| {
+        let visit_date = row.get::<_, Option<i64>>(3)?.unwrap_or(0);
+        Ok(HistoryEntry {
+          id: row.get::<_, i64>(0)? as u64,
+          profile: None,
+          device: String::new(),
+          os: String::new(),
+          visit_type: 0,
+          visit_type_name: String::new(),
+          url: row.get(1)?,
+          title: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+          visit_date,
+          date: format_visit_date(visit_date, None),
+          tz_offset_seconds: visit_date_offset(visit_date),
+          dwell_ms: None,
+          visit_count: Some(row.get::<_, i64>(4)? as u64),
+          frecency: Some(row.get(5)?),
+          url_truncated: false,
+        })
+      });
+    }
+
+    let visits = Profile::read_pages(conn, from_id, to_id, query, limit, |row| {
+      Ok(MozHistoryVisits {
+        id: row.get(0)?,
+        place_id: row.get(1)?,
+        visit_date: row.get(2)?,
+        visit_type: row.get(3)?,
+      })
+    })?;
+    Profile::history_entries(conn, visits, query)
+  }
+
+  // Rows of history_sql with ids between `from_id` and `to_id`, read in id
+  // ordered pages so a huge range doesn't hold one long running statement
+  // over the whole table. The id must be the first column. Every page is
+  // collected into the result, so memory grows with the range or `limit`;
+  // checkpoint_pages sets `limit` to page_size.
+  fn read_pages<T>(
+    conn: &Connection,
+    from_id: u64,
+    to_id: u64,
+    query: &HistoryQuery,
+    limit: Option<usize>,
+    map: impl Fn(&rusqlite::Row) -> rusqlite::Result<T>,
+  ) -> rusqlite::Result<Vec<T>> {
+    let mut rows: Vec<T> = vec![];
+    let page_size = query.page_size;
+    let to_id = to_id as i64;
+
+    let mut stmt = conn.prepare(&Profile::history_sql(query))?;
+    let search = query.search_pattern();
+    let mut cursor = from_id as i64 - 1;
+    loop {
+      let mut named: Vec<(&str, &dyn ToSql)> =
+        vec![(":cursor", &cursor), (":to_id", &to_id), (":page_size", &page_size)];
+      if let Some(search) = &search {
+        named.push((":search", search));
+      }
+      let mut read = 0;
+      let mut page = stmt.query_named(&named)?;
+      while let Some(row) = page.next()? {
+        let id: i64 = row.get(0)?;
+        rows.push(map(row)?);
+        read += 1;
+        cursor = id;
+        if Some(rows.len()) == limit {
+          return Ok(rows);
+        }
+      }
+      if read < page_size as usize {
+        break;
+      }
+    }
+    Ok(rows)
+  }
+
+  // The page query of read_history_range
+  fn history_sql(query: &HistoryQuery) -> String {
+    let columns = match query.source {
+      Source::Visits => "id, place_id, visit_date, visit_type",
+      Source::Places => "id, url, title, last_visit_date, visit_count, frecency",
+    };
+    format!(
+      "SELECT {} FROM {} where id > :cursor AND id <= :to_id{} ORDER BY id LIMIT :page_size",
+      columns,
+      query.table(),
+      query.search_clause()
+    )
+  }
+
+  // Logs the first page query get_history runs after `from_id` and how
+  // SQLite plans it
+  fn explain_history(&self, conn: &Connection, from_id: u64, query: &HistoryQuery) -> rusqlite::Result<()> {
+    let sql = Profile::history_sql(query);
+    let (cursor, to_id) = (from_id as i64, i64::MAX);
+    let search = query.search_pattern();
+    let mut named: Vec<(&str, &dyn ToSql)> =
+      vec![(":cursor", &cursor), (":to_id", &to_id), (":page_size", &query.page_size)];
+    let mut bound = format!(":cursor = {}, :to_id = {}, :page_size = {}", cursor, to_id, query.page_size);
+    if let Some(search) = &search {
+      named.push((":search", search));
+      bound.push_str(&format!(", :search = {:?}", search));
+    }
+    Log::info(format!("SQL: {} with {}", sql, bound)).profile(&self.name).emit();
+
+    let plan = conn
+      .prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?
+      .query_map_named(&named, |row| row.get::<_, String>(3))?
+      .collect::<rusqlite::Result<Vec<_>>>()?;
+    for detail in plan {
+      Log::info(format!("Plan: {}", detail)).profile(&self.name).emit();
+    }
+    Ok(())
+  }
+
+  // Visits in `conn` that the database at `other` doesn't hold, comparing
+  // id, place and date so reused ids count as new visits
+  fn get_visits_missing_from(conn: &Connection, other: &Path) -> rusqlite::Result<Vec<MozHistoryVisits>> {
+    conn.execute("ATTACH DATABASE ? AS other", params![other.to_string_lossy()])?;
+    let visits = conn
+      .prepare(
+        "SELECT id, place_id, visit_date, visit_type FROM moz_historyvisits v \
+         WHERE NOT EXISTS (SELECT 1 FROM other.moz_historyvisits o \
+         WHERE o.id = v.id AND o.place_id = v.place_id AND o.visit_date = v.visit_date) \
+         ORDER BY id",
+      )?
+      .query_map(params![], |row| {
+        Ok(MozHistoryVisits {
+          id: row.get(0)?,
+          place_id: row.get(1)?,
+          visit_date: row.get(2)?,
+          visit_type: row.get(3)?,
+        })
+      })?
+      .collect::<rusqlite::Result<Vec<_>>>()?;
+    conn.execute("DETACH DATABASE other", params![])?;
+    Ok(visits)
+  }
+
+  // The most recent visit of every url and its total visit count. SQLite
+  // takes the bare id and visit_type columns from the row holding max()
+  fn get_latest_per_url(conn: &Connection, query: &HistoryQuery) -> rusqlite::Result<Vec<HistoryEntry>> {
+    let latest = conn
+      .prepare(
+        "SELECT id, place_id, max(visit_date), visit_type, count(*) FROM moz_historyvisits \
+         GROUP BY place_id ORDER BY max(visit_date), id",
+      )?
+      .query_map(params![], |row| {
+        let visit = MozHistoryVisits {
+          id: row.get(0)?,
+          place_id: row.get(1)?,
+          visit_date: row.get(2)?,
+          visit_type: row.get(3)?,
+        };
+        Ok((visit, row.get::<_, i64>(4)?))
+      })?
+      .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let visits = latest.iter().map(|(visit, _)| (visit.id, visit.place_id));
+    let mut places = Profile::get_place_entries(conn, visits, query)?;
+    let mut entries = vec![];
+    for (visit, count) in latest {
+      let place = match places.remove(&visit.place_id) {
+        Some(place) => place,
+        None => continue,
+      };
+      entries.push(HistoryEntry {
+        id: visit.id as u64,
+        profile: None,
+        device: String::new(),
+        os: String::new(),
+        visit_type: visit.visit_type,
+        visit_type_name: visit_type_name(visit.visit_type).to_string(),
+        url: place.url,
+        title: place.title,
+        visit_date: visit.visit_date,
+        date: format_visit_date(visit.visit_date, None),
+        tz_offset_seconds: visit_date_offset(visit.visit_date),
+        dwell_ms: None,
+        visit_count: Some(count as u64),
+        frecency: None,
+        url_truncated: false,
+      });
+    }
+    Ok(query.redaction.apply(entries))
+  }
+
+  // Snapshot of the last exported run, kept with diff_mode
+  fn previous_db_path(&self) -> PathBuf {
+    self.directory.join(format!("previous_{}", self.database_filename))
+  }
+
+  // Redacted visits added since the previous snapshot, and the ones deleted
+  // from it
+  fn get_history_diff(
+    &self,
+    current: &Connection,
+    query: &HistoryQuery,
+  ) -> rusqlite::Result<(ReadHistory, Vec<HistoryEntry>)> {
+    let previous_path = self.previous_db_path();
+    let previous = Connection::open_with_flags(&previous_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let added = Profile::get_visits_missing_from(current, &previous_path)?;
+    let added = Profile::history_entries(current, added, query)?;
+    let deleted = Profile::get_visits_missing_from(&previous, &self.db_path)?;
+    Ok((
+      ReadHistory {
+        read: added.len(),
+        read_to: added.iter().map(|e| e.id).max().unwrap_or(0),
+        entries: query.redaction.apply(added),
+      },
+      query.redaction.apply(Profile::history_entries(&previous, deleted, query)?),
+    ))
+  }
+
+  // Looks up the places of `visits`, keeping their order
+  fn history_entries(
+    conn: &Connection,
+    visits: Vec<MozHistoryVisits>,
+    query: &HistoryQuery,
+  ) -> rusqlite::Result<Vec<HistoryEntry>> {
+    let places = Profile::get_place_entries(conn, visits.iter().map(|visit| (visit.id, visit.place_id)), query)?;
+    let mut history_entries: Vec<HistoryEntry> = vec![];
+    for entry in visits {
+      let place = match places.get(&entry.place_id) {
+        Some(place) => place,
+        None => continue,
+      };
+      history_entries.push(HistoryEntry {
+        id: entry.id as u64,
+        profile: None,
+        device: String::new(),
+        os: String::new(),
+        visit_type: entry.visit_type,
+        visit_type_name: visit_type_name(entry.visit_type).to_string(),
+        url: place.url.clone(),
+        title: place.title.clone(),
+        visit_date: entry.visit_date,
+        date: format_visit_date(entry.visit_date, None),
+        tz_offset_seconds: visit_date_offset(entry.visit_date),
+        dwell_ms: None,
+        visit_count: None,
+        frecency: None,
+        url_truncated: false,
+      })
+    }
+
+    // The next visit is the next one by date, found in a sorted copy of the
+    // order so the entries stay in id order
+    let mut by_date: Vec<usize> = (0..history_entries.len()).collect();
+    by_date.sort_by_key(|&i| (history_entries[i].visit_date, history_entries[i].id));
+    for pair in by_date.windows(2) {
+      let gap = visit_date_micros(history_entries[pair[1]].visit_date)
+        - visit_date_micros(history_entries[pair[0]].visit_date);
+      history_entries[pair[0]].dwell_ms = Some(gap / 1000);
+    }
+
+    Ok(history_entries)
+  }
+
+  // Database wide totals giving context to an incremental export
+  fn get_stats(&self, conn: &Connection) -> rusqlite::Result<ProfileStats> {
+    let total_places = conn.query_row("SELECT count(*) FROM moz_places", params![], |row| row.get(0))?;
+    let (total_visits, oldest_visit_date, newest_visit_date) = conn.query_row(
+      "SELECT count(*), min(visit_date), max(visit_date) FROM moz_historyvisits",
+      params![],
+      |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+
+    Ok(ProfileStats {
+      total_places,
+      total_visits,
+      oldest_visit_date,
+      newest_visit_date,
+      db_size_bytes: fs::metadata(&self.db_path).map(|m| m.len()).unwrap_or(0),
+    })
+  }
+}
+
+// Host part of a url, None for urls without one (about:, data:, file:///)
+fn url_domain(url: &str) -> Option<&str> {
+  let rest = &url[url.find("://")? + 3..];
+  let host = rest.split(['/', '?', '#']).next()?;
+  let host = host.rsplit('@').next()?;
+  let host = host.split(':').next()?;
+  if host.is_empty() {
+    None
+  } else {
+    Some(host)
+  }
+}
+
+// Buckets visits by day in the configured timezone with the most visited domains of each day.
+fn summarize_history(history: &[HistoryEntry], top_domains: usize) -> Vec<DailySummary> {
+  let mut days: BTreeMap<String, Vec<&HistoryEntry>> = BTreeMap::new();
+  for entry in history {
+    let day = format_visit_date(entry.visit_date, Some("%Y-%m-%d"));
+    days.entry(day).or_default().push(entry);
+  }
+
+  days
+    .into_iter()
+    .map(|(date, entries)| {
+      let unique_urls = entries
+        .iter()
+        .map(|e| e.url.as_str())
+        .collect::<std::collections::HashSet<_>>()
+        .len();
+
+      let mut domains: BTreeMap<&str, usize> = BTreeMap::new();
+      for domain in entries.iter().filter_map(|e| url_domain(&e.url)) {
+        *domains.entry(domain).or_default() += 1;
+      }
+      let mut domains: Vec<(&str, usize)> = domains.into_iter().collect();
+      domains.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+      domains.truncate(top_domains);
+
+      DailySummary {
+        date,
+        total_visits: entries.len(),
+        unique_urls,
+        top_domains: domains
+          .into_iter()
+          .map(|(domain, count)| DomainCount {
+            domain: domain.to_string(),
+            count,
+          })
+          .collect(),
+      }
+    })
+    .collect()
+}
+
+// A label value in the text exposition format
+fn escape_label(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Writes Prometheus text exposition metrics for node_exporter's textfile
+// collector. The file is renamed into place so it is never read half written.
+fn write_textfile_metrics(path: &Path, profiles: &[Profile]) {
+  let mut metrics = String::new();
+
+  metrics.push_str("# HELP firefox_history_last_visit_id Cursor into moz_historyvisits.\n");
+  metrics.push_str("# TYPE firefox_history_last_visit_id gauge\n");
+  for profile in profiles {
+    metrics.push_str(&format!(
+      "firefox_history_last_visit_id{{profile=\"{}\"}} {}\n",
+      escape_label(&profile.name),
+      profile.state.last_historyvisit_id
+    ));
+  }
+
+  metrics.push_str("# HELP firefox_history_exported_total History entries exported.\n");
+  metrics.push_str("# TYPE firefox_history_exported_total counter\n");
+  for profile in profiles {
+    metrics.push_str(&format!(
+      "firefox_history_exported_total{{profile=\"{}\"}} {}\n",
+      escape_label(&profile.name),
+      profile.state.exported_total
+    ));
+  }
+
+  metrics.push_str("# HELP firefox_history_visits_by_type_total History entries exported per visit type.\n");
+  metrics.push_str("# TYPE firefox_history_visits_by_type_total counter\n");
+  for profile in profiles {
+    for (name, count) in &profile.state.exported_by_type {
+      metrics.push_str(&format!(
+        "firefox_history_visits_by_type_total{{profile=\"{}\",type=\"{}\"}} {}\n",
+        escape_label(&profile.name),
+        escape_label(name),
+        count
+      ));
+    }
+  }
+
+  metrics.push_str("# HELP firefox_history_last_sync_timestamp_seconds Last run that exported entries.\n");
+  metrics.push_str("# TYPE firefox_history_last_sync_timestamp_seconds gauge\n");
+  for profile in profiles {
+    metrics.push_str(&format!(
+      "firefox_history_last_sync_timestamp_seconds{{profile=\"{}\"}} {}\n",
+      escape_label(&profile.name),
+      profile.state.last_sync / 1000
+    ));
+  }
+
+  let temp_path = path.with_extension("prom.tmp");
+  fs::write(&temp_path, metrics).unwrap();
+  fs::rename(&temp_path, path).unwrap();
+}
+
+// What a post_export_command learns about the export through FE_* variables
+struct PostExportHook<'a> {
+  // None for the merged export
+  profile: Option<&'a str>,
+  outputs: &'a [PathBuf],
+  count: usize,
+  cursor: u64,
+}
+
+impl PostExportHook<'_> {
+  // The outputs one per line, as paths may hold any other separator
+  fn output_files(&self) -> std::ffi::OsString {
+    let mut files = std::ffi::OsString::new();
+    for (i, output) in self.outputs.iter().enumerate() {
+      if i > 0 {
+        files.push("\n");
+      }
+      files.push(output);
+    }
+    files
+  }
+
+  fn run(&self, command: &str, strict: bool) {
+    let mut cmd = std::process::Command::new("sh");
+    cmd
+      .arg("-c")
+      .arg(command)
+      .env("FE_OUTPUT_FILE", self.output_files())
+      .env("FE_COUNT", self.count.to_string())
+      .env("FE_CURSOR", self.cursor.to_string());
+    if let Some(profile) = self.profile {
+      cmd.env("FE_PROFILE", profile);
+    }
+
+    let log = |log: Log| match self.profile {
+      Some(profile) => log.profile(profile).emit(),
+      None => log.emit(),
+    };
+    let output = match cmd.output() {
+      Ok(output) => output,
+      Err(err) => {
+        log(Log::error(format!("Could not run post_export_command: {}", err)));
+        if strict {
+          std::process::exit(1);
+        }
+        return;
+      }
+    };
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+      log(Log::info(format!("post_export_command: {}", line)));
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+      log(Log::warn(format!("post_export_command: {}", line)));
+    }
+    if !output.status.success() {
+      log(Log::error(format!("post_export_command failed with {}", output.status)));
+      if strict {
+        std::process::exit(1);
+      }
+    }
+  }
+}
+
+fn write_json_to_file<T: Serialize + ?Sized>(value: &T, filename: &Path, pretty: bool) {
+  write_json(value, create_file(filename), pretty);
+}
+
+fn create_file(filename: &Path) -> BufWriter<fs::File> {
+  let file = fs::OpenOptions::new()
+    .create(true)
+    .write(true)
+    .truncate(true)
+    .open(filename)
+    .unwrap();
+  BufWriter::new(file)
+}
+
+// Objects are written with their keys sorted alphabetically, whatever the
+// field order of the struct, so exports of the same data are identical and
+// diff line by line. Going through a Value does this as serde_json's Map is a
+// BTreeMap; the preserve_order feature must stay off.
+fn write_json<T: Serialize + ?Sized, W: Write>(value: &T, writer: W, pretty: bool) {
+  let value = serde_json::to_value(value).unwrap();
+  if pretty {
+    serde_json::to_writer_pretty(writer, &value).unwrap();
+  } else {
+    serde_json::to_writer(writer, &value).unwrap();
+  }
+}
+
+// How exports are written
+#[derive(Debug)]
+struct ExportOptions {
+  pretty: bool,
+  // Set by --force
+  force: bool,
+  compression: Compression,
+  // Writes a .sig of every export when set
+  signing_key: Option<SigningKey>,
+  // History exports become {"<key>": [...]} when set
+  root_key: Option<String>,
+}
+
+// Reads a hex encoded Ed25519 secret key. Errors never include the contents.
+fn read_signing_key(path: &Path) -> SigningKey {
+  let key: Result<[u8; 32], String> = fs::read_to_string(path)
+    .map_err(|e| e.to_string())
+    .and_then(|raw| hex::decode(raw.trim()).map_err(|_| "not hex encoded".to_string()))
+    .and_then(|bytes| bytes.try_into().map_err(|_| "not 32 bytes long".to_string()));
+  match key {
+    Ok(bytes) => SigningKey::from_bytes(&bytes),
+    Err(err) => {
+      Log::error(format!("Invalid signing_key \"{}\": {}", path.to_string_lossy(), err)).emit();
+      std::process::exit(1);
+    }
+  }
+}
+
+// Path of the detached signature of `filename`
+fn signature_path(filename: &Path) -> PathBuf {
+  let mut path = filename.as_os_str().to_owned();
+  path.push(".sig");
+  PathBuf::from(path)
+}
+
+// Checks the .sig next to `filename` against a hex encoded public key
+fn verify_signature(filename: &Path, public_key: &Path) -> Result<(), String> {
+  let key = fs::read_to_string(public_key).map_err(|e| e.to_string())?;
+  let key: [u8; 32] = hex::decode(key.trim())
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or("public key must be 32 hex encoded bytes")?;
+  let key = VerifyingKey::from_bytes(&key).map_err(|e| e.to_string())?;
+
+  let signature = fs::read_to_string(signature_path(filename)).map_err(|e| e.to_string())?;
+  let signature: [u8; 64] = hex::decode(signature.trim())
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or("signature must be 64 hex encoded bytes")?;
+
+  let contents = fs::read(filename).map_err(|e| e.to_string())?;
+  key
+    .verify(&contents, &Signature::from_bytes(&signature))
+    .map_err(|e| e.to_string())
+}
+
+// Writes an export, refusing to replace an existing file unless forced.
+// Returns the path written, `filename` with the compression's extension.
+fn write_export<T: Serialize + ?Sized>(value: &T, filename: &Path, options: &ExportOptions) -> PathBuf {
+  let (pretty, compression) = (options.pretty, options.compression);
+  let mut path = filename.as_os_str().to_owned();
+  path.push(compression.algo.extension());
+  let path = PathBuf::from(path);
+
+  if !options.force && path.exists() {
+    Log::error(format!(
+      "Export \"{}\" already exists, pass --force to overwrite it",
+      path.to_string_lossy()
+    ))
+    .emit();
+    std::process::exit(1);
+  }
+  match compression.algo {
+    CompressionAlgo::None => write_json_to_file(value, &path, pretty),
+    CompressionAlgo::Gzip => {
+      let level = flate2::Compression::new(compression.level.unwrap_or(6) as u32);
+      let mut encoder = GzEncoder::new(create_file(&path), level);
+      write_json(value, &mut encoder, pretty);
+      encoder.finish().unwrap();
+    }
+    CompressionAlgo::Zstd => {
+      let mut encoder = zstd::Encoder::new(create_file(&path), compression.level.unwrap_or(0)).unwrap();
+      write_json(value, &mut encoder, pretty);
+      encoder.finish().unwrap();
+    }
+  }
+
+  if let Some(key) = &options.signing_key {
+    let signature = key.sign(&fs::read(&path).unwrap());
+    fs::write(signature_path(&path), hex::encode(signature.to_bytes())).unwrap();
+  }
+  path
+}
+
+fn write_history_to_file(history: &[HistoryEntry], filename: &Path, options: &ExportOptions) -> PathBuf {
+  match &options.root_key {
+    Some(key) => {
+      let mut root = BTreeMap::new();
+      root.insert(key.as_str(), history);
+      write_export(&root, filename, options)
+    }
+    None => write_export(history, filename, options),
+  }
+}
+
+// A history export as a bare array, whether or not it was written with
+// json_root_key. Anything else is returned as is.
+fn strip_root_key(value: serde_json::Value) -> serde_json::Value {
+  match value {
+    serde_json::Value::Object(map) if map.len() == 1 && map.values().all(|v| v.is_array()) => {
+      map.into_iter().next().unwrap().1
+    }
+    value => value,
+  }
+}
+
+fn read_history_file(filename: &Path) -> Result<Vec<HistoryEntry>, String> {
+  let file = open_json_file(filename).map_err(|e| e.to_string())?;
+  let value: serde_json::Value = serde_json::from_reader(file).map_err(|e| e.to_string())?;
+  serde_json::from_value(strip_root_key(value)).map_err(|e| e.to_string())
+}
+
+// One entry per line, tagged with its profile as the stream may carry several
+fn write_ndjson<W: Write>(writer: &mut W, profile: &str, history: Vec<HistoryEntry>) -> std::io::Result<()> {
+  for mut entry in history {
+    entry.profile = Some(profile.to_string());
+    writeln!(writer, "{}", serde_json::to_value(&entry).unwrap())?;
+  }
+  writer.flush()
+}
+
+// A reader that stops early, like `| head`, ends the run cleanly, any other
+// error exits 1. Either way the cursor is not saved.
+fn stream_to_stdout<W: Write>(writer: &mut W, profile: &str, history: Vec<HistoryEntry>) {
+  match write_ndjson(writer, profile, history) {
+    Ok(()) => {}
+    Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => {
+      Log::info("stdout was closed, stopping").profile(profile).emit();
+      std::process::exit(0);
+    }
+    Err(err) => {
+      Log::error(format!("Writing to stdout failed: {}", err)).profile(profile).emit();
+      std::process::exit(1);
+    }
+  }
+}
+
+// Each line is a gzip member of its own, so entries can be appended to the
+// file without rewriting it and gunzip still reads it as a whole
+fn append_history_to_gzip_log(history: Vec<HistoryEntry>, filename: &Path) -> std::io::Result<()> {
+  let mut file = fs::OpenOptions::new().create(true).append(true).open(filename)?;
+  for entry in history {
+    let mut member = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    writeln!(member, "{}", serde_json::to_value(&entry).unwrap())?;
+    file.write_all(&member.finish()?)?;
+  }
+  file.sync_all()
+}
+
+// Where `output` streams NDJSON to
+#[derive(Debug)]
+enum StreamOutput {
+  Fifo(PathBuf),
+  UnixSocket(PathBuf),
+}
+
+impl StreamOutput {
+  fn path(&self) -> &Path {
+    match self {
+      StreamOutput::Fifo(path) | StreamOutput::UnixSocket(path) => path,
+    }
+  }
+
+  // Returns once every entry is flushed. Opening a FIFO for writing blocks
+  // until a reader has it open, a socket nobody listens on fails right away.
+  fn write(&self, history: Vec<HistoryEntry>, profile: &str) -> std::io::Result<()> {
+    match self {
+      StreamOutput::Fifo(path) => {
+        let file = fs::OpenOptions::new().write(true).open(path)?;
+        write_ndjson(&mut BufWriter::new(file), profile, history)
+      }
+      StreamOutput::UnixSocket(path) => write_history_to_socket(history, path, profile),
+    }
+  }
+}
+
+#[cfg(unix)]
+fn write_history_to_socket(history: Vec<HistoryEntry>, path: &Path, profile: &str) -> std::io::Result<()> {
+  let stream = std::os::unix::net::UnixStream::connect(path)?;
+  let mut writer = BufWriter::new(&stream);
+  write_ndjson(&mut writer, profile, history)?;
+  drop(writer);
+  // Tells the consumer this profile's entries are complete
+  stream.shutdown(std::net::Shutdown::Write)
+}
+
+#[cfg(not(unix))]
+fn write_history_to_socket(_history: Vec<HistoryEntry>, _path: &Path, _profile: &str) -> std::io::Result<()> {
+  Err(std::io::Error::new(
+    std::io::ErrorKind::Other,
+    "unix sockets are only supported on unix",
+  ))
+}
+
+// What to do when streaming a profile to `output` fails. Either way its
+// cursor stays put, so the entries are sent again on the next run.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum OutputErrorPolicy {
+  // Abort the whole run
+  #[default]
+  Fail,
+  // Log the error and go on with the next profile
+  Skip,
+}
+
+// An entry of a profile's index.json, describing one history export
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexRecord {
+  file: String,
+  // Run timestamp in milliseconds
+  timestamp: u64,
+  from_id: u64,
+  to_id: u64,
+  count: usize,
+  format: String,
+  compression: CompressionAlgo,
+  // Snapshot the export was read from
+  #[serde(default)]
+  source_db: Option<SourceDb>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct SourceDb {
+  path: PathBuf,
+  // Modification time in milliseconds
+  mtime: u64,
+  size: u64,
+}
+
+impl SourceDb {
+  fn of(path: &Path) -> Option<SourceDb> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+    Some(SourceDb {
+      path: path.to_path_buf(),
+      mtime: mtime.as_millis() as u64,
+      size: metadata.len(),
+    })
+  }
+}
+
+// Appends `records` to index.json in `directory`, dropping records of
+// exports that no longer exist. The index is replaced atomically.
+fn update_index(directory: &Path, records: Vec<IndexRecord>) {
+  let path = directory.join("index.json");
+  let mut index: Vec<IndexRecord> = if path.exists() {
+    serde_json::from_reader(BufReader::new(fs::File::open(&path).unwrap())).unwrap()
+  } else {
+    vec![]
+  };
+  index.extend(records);
+  index.retain(|record| directory.join(&record.file).exists());
+
+  let temp_path = path.with_extension("json.tmp");
+  write_json_to_file(&index, &temp_path, true);
+  fs::rename(&temp_path, &path).unwrap();
+}
+
+// Opens a JSON file for reading, decompressing .gz and .zst exports
+fn open_json_file(filename: &Path) -> std::io::Result<Box<dyn Read>> {
+  let file = BufReader::new(fs::File::open(filename)?);
+  Ok(match filename.extension().and_then(|e| e.to_str()) {
+    Some("gz") => Box::new(GzDecoder::new(file)),
+    Some("zst") => Box::new(zstd::Decoder::with_buffer(file)?),
+    _ => Box::new(file),
+  })
+}
+
+// The run timestamp as written in export file names. Characters that are not
+// safe in a file name across platforms, and the _ separating file numbers,
+// are replaced with -
+fn file_timestamp(millis: u128, format: Option<&str>) -> String {
+  let format = match format {
+    Some(format) => format,
+    None => return millis.to_string(),
+  };
+  let millis = millis as i64;
+  let rendered = match TIMEZONE.get().copied().unwrap_or_default() {
+    Timezone::Local => Local.timestamp_millis_opt(millis).unwrap().format(format).to_string(),
+    Timezone::Utc => Utc.timestamp_millis_opt(millis).unwrap().format(format).to_string(),
+  };
+  rendered
+    .chars()
+    .map(|c| match c {
+      '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '_' => '-',
+      c if c.is_control() || c.is_whitespace() => '-',
+      c => c,
+    })
+    .collect()
+}
+
+// <YYYY>/<MM>/<DD> of the run in the configured timezone, for
+// date_partitioned exports
+fn date_partition(millis: u128) -> PathBuf {
+  let millis = millis as i64;
+  let day = match TIMEZONE.get().copied().unwrap_or_default() {
+    Timezone::Local => Local.timestamp_millis_opt(millis).unwrap().format("%Y/%m/%d").to_string(),
+    Timezone::Utc => Utc.timestamp_millis_opt(millis).unwrap().format("%Y/%m/%d").to_string(),
+  };
+  day.split('/').collect()
+}
+
+// What to do when a run's export names are already taken, e.g. by an earlier
+// run in the same millisecond or with a coarse filename_timestamp_format
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum DuplicateFilenames {
+  // Stop with an error unless --force overwrites them
+  #[default]
+  Fail,
+  // Add the cursor before and after the run to the timestamp, and a counter
+  // when even that is taken
+  Range,
+}
+
+// `stamp`-<cursor>-<read_to>, or with -2, -3... appended until no file of
+// the profile's directory or date partition uses it
+fn unique_stamp(directory: &Path, profile: &str, now: u128, stamp: &str, cursor: u64, read_to: u64) -> String {
+  let base = format!("{}-{}-{}", stamp, cursor, read_to);
+  let partition = directory.join(date_partition(now));
+  let names: Vec<String> = [directory, partition.as_path()]
+    .iter()
+    .filter_map(|dir| fs::read_dir(dir).ok())
+    .flatten()
+    .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+    .collect();
+  // Matches <kind>_export_<stamp>[_<n>].json[.ext] and <profile>-<stamp>...
+  let taken = |stamp: &str| {
+    names.iter().any(|name| {
+      let rest = match name.find(&format!("_export_{}", stamp)) {
+        Some(at) => &name[at + "_export_".len() + stamp.len()..],
+        None => match name.strip_prefix(&format!("{}-{}", profile, stamp)) {
+          Some(rest) => rest,
+          None => return false,
+        },
+      };
+      rest.starts_with('.') || rest.starts_with('_')
+    })
+  };
+
+  let mut candidate = base.clone();
+  let mut n = 1;
+  while taken(&candidate) {
+    n += 1;
+    candidate = format!("{}-{}", base, n);
+  }
+  candidate
+}
+
+// Timestamp and file number of a history_export_<ts>[_<n>].json name.
+// Timestamps compare as strings, which orders both epoch milliseconds and
+// year first filename_timestamp_format patterns
+fn export_file_key(filename: &str) -> Option<(String, u32)> {
+  let filename = [".gz", ".zst"]
+    .iter()
+    .find_map(|ext| filename.strip_suffix(ext))
+    .unwrap_or(filename);
+  let stem = filename.strip_prefix("history_export_")?.strip_suffix(".json")?;
+  let mut parts = stem.splitn(2, '_');
+  let timestamp = parts.next()?.to_string();
+  let number = match parts.next() {
+    Some(number) => number.parse().ok()?,
+    None => 0,
+  };
+  Some((timestamp, number))
+}
+
+// Removes all but the newest `retain` history exports in `directory`,
+// returning the removed files. Files not named like an export are left alone.
+fn prune_exports(directory: &Path, retain: usize) -> Vec<PathBuf> {
+  let mut exports: Vec<((String, u32), PathBuf)> = fs::read_dir(directory)
+    .unwrap()
+    .filter_map(|entry| entry.ok().map(|e| e.path()))
+    .filter_map(|path| {
+      let key = export_file_key(path.file_name()?.to_str()?)?;
+      Some((key, path))
+    })
+    .collect();
+  exports.sort();
+
+  let excess = exports.len().saturating_sub(retain);
+  exports
+    .into_iter()
+    .take(excess)
+    .map(|(_, path)| {
+      fs::remove_file(&path).unwrap();
+      let signature = signature_path(&path);
+      if signature.exists() {
+        fs::remove_file(signature).unwrap();
+      }
+      path
+    })
+    .collect()
+}
+
+// Highest visit id in a previous history export
+fn last_exported_id(filename: &Path) -> u64 {
+  let history = match read_history_file(filename) {
+    Ok(history) => history,
+    Err(err) => {
+      Log::error(format!("Can't read \"{}\": {}", filename.to_string_lossy(), err)).emit();
+      std::process::exit(1);
+    }
+  };
+
+  match history.iter().map(|e| e.id).max() {
+    Some(id) if id > 0 => id,
+    _ => {
+      Log::error(format!("No visit ids found in \"{}\"", filename.to_string_lossy())).emit();
+      std::process::exit(1);
+    }
+  }
+}
+
+// Reads a written export back and checks it holds the expected entries.
+fn verify_history_file(filename: &Path, expected: usize) -> Result<(), String> {
+  let history = read_history_file(filename)?;
+
+  if history.len() != expected {
+    return Err(format!("expected {} entries, found {}", expected, history.len()));
+  }
+  Ok(())
+}
+
+// Prints entries as they show up in the snapshot, one JSON object per line.
+// Files are never written and state.json only moves forward with --commit.
+fn tail(context: &mut Context, interval: u64, commit: bool) {
+  let mut cursors: Vec<u64> = context
+    .profiles
+    .iter()
+    .map(|p| p.state.last_historyvisit_id)
+    .collect();
+  let query = context.query.clone();
+
+  loop {
+    context.backup_places();
+    for (profile, cursor) in context.profiles.iter_mut().zip(cursors.iter_mut()) {
+      if profile.stalled {
+        continue;
+      }
+      let history = match context
+        .on_profile_error
+        .apply(context.profile_retries, profile, |p| {
+          let conn = p.open()?;
+          Ok(Profile::get_history(&conn, *cursor, &query)?)
+        })
+      {
+        Some(history) if history.read > 0 => history,
+        _ => continue,
+      };
+      let read_to = history.read_to;
+
+      for mut entry in context.filter.apply(&profile.name, history.entries) {
+        entry.profile = Some(profile.name.clone());
+        println!("{}", serde_json::to_value(&entry).unwrap());
+      }
+      *cursor = read_to;
+
+      if commit {
+        profile.state.last_historyvisit_id = *cursor;
+        profile.state.last_sync = SystemTime::now()
+          .duration_since(SystemTime::UNIX_EPOCH)
+          .unwrap()
+          .as_millis() as u64;
+        profile.save_state();
+      }
+    }
+
+    std::thread::sleep(std::time::Duration::from_secs(interval));
+  }
+}
+
+// Visits past each profile's cursor that match --search, from a fresh
+// snapshot or with `live` straight from Firefox's database
+fn count(context: &mut Context, since: Option<u64>, live: bool) {
+  if !live {
+    context.backup_places();
+  }
+  let sql = format!(
+    "SELECT count(*) FROM {} WHERE id > :cursor{}",
+    context.query.table(),
+    context.query.search_clause()
+  );
+  let search = context.query.search_pattern();
+  let mut total = 0;
+  for profile in context.profiles.iter().filter(|p| !p.stalled) {
+    let cursor = since.unwrap_or(profile.state.last_historyvisit_id) as i64;
+    let mut named: Vec<(&str, &dyn ToSql)> = vec![(":cursor", &cursor)];
+    if let Some(search) = &search {
+      named.push((":search", search));
+    }
+    let immutable = context.immutable;
+    let pending = context.on_profile_error.apply(context.profile_retries, profile, |p| {
+      let query = |conn: &Connection| conn.query_row_named(&sql, &named, |row| row.get::<_, i64>(0));
+      if live && !context.offline {
+        let source = p.path.join(&p.database_filename);
+        let owned_source = source.clone();
+        let conn = with_open_timeout(&source, move || open_source(&owned_source, immutable))??;
+        Ok(query(&conn)?)
+      } else {
+        let conn = p.open()?;
+        Ok(query(&conn)?)
+      }
+    });
+    if let Some(pending) = pending {
+      println!("{:<20} {:>10}", profile.name, pending);
+      total += pending;
+    }
+  }
+  println!("{:<20} {:>10}", "total", total);
+}
+
+// Where the next run would pick up, to tell a stuck cursor from a quiet
+// profile. Snapshots are refreshed, nothing is exported.
+fn preview_cursor(context: &mut Context, since: Option<u64>) {
+  context.backup_places();
+  println!("{:<20} {:>12} {:>12} {:>10}", "profile", "cursor", "max_id", "pending");
+  let sql = format!(
+    "SELECT (SELECT ifnull(max(id), 0) FROM {0}), (SELECT count(*) FROM {0} WHERE id > :cursor)",
+    context.query.table()
+  );
+  for profile in context.profiles.iter().filter(|p| !p.stalled) {
+    let cursor = since.unwrap_or(profile.state.last_historyvisit_id);
+    let counts = context.on_profile_error.apply(context.profile_retries, profile, |p| {
+      let conn = p.open()?;
+      Ok(conn.query_row(
+        &sql,
+        params![cursor as i64],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+      )?)
+    });
+    if let Some((max_id, pending)) = counts {
+      println!("{:<20} {:>12} {:>12} {:>10}", profile.name, cursor, max_id, pending);
+    }
+  }
+}
+
+// JSON Schema of a history_export file, following export_titles,
+// tag_profile and json_root_key
+fn export_schema(context: &Context) -> serde_json::Value {
+  let mut entry = serde_json::to_value(schemars::schema_for!(HistoryEntry)).unwrap();
+  let object = entry.as_object_mut().unwrap();
+  object.remove("$schema");
+  if titles_omitted(&String::new()) {
+    object["properties"].as_object_mut().unwrap().remove("title");
+  }
+  if context.filter.tag_profile {
+    object["required"].as_array_mut().unwrap().push("profile".into());
+  }
+
+  let list = serde_json::json!({ "type": "array", "items": entry });
+  let mut schema = match &context.export.root_key {
+    Some(key) => serde_json::json!({
+      "type": "object",
+      "required": [key],
+      "properties": { key.as_str(): list },
+    }),
+    None => list,
+  };
+  let schema_object = schema.as_object_mut().unwrap();
+  schema_object.insert("$schema".into(), "http://json-schema.org/draft-07/schema#".into());
+  schema_object.insert("title".into(), "firefox-exporter history export".into());
+  schema_object.insert("version".into(), EXPORT_SCHEMA_VERSION.into());
+  schema
+}
+
+// Integrity and foreign key checks of every snapshot as it is on disk,
+// true when all pass
+fn validate_db(context: &Context) -> bool {
+  let mut valid = true;
+  for profile in &context.profiles {
+    let mut problems = vec![];
+    match profile.open() {
+      Ok(conn) => {
+        if let Err(err) = integrity_check(&conn) {
+          problems.push(err);
+        }
+        match foreign_key_check(&conn) {
+          Ok(violations) => problems.extend(violations),
+          Err(err) => problems.push(err.to_string()),
+        }
+      }
+      Err(err) => problems.push(err.to_string()),
+    }
+    if problems.is_empty() {
+      println!("{}: ok", profile.name);
+    } else {
+      valid = false;
+      for problem in problems {
+        println!("{}: {}", profile.name, problem);
+      }
+    }
+  }
+  valid
+}
+
+// Rows of PRAGMA foreign_key_check, one line each
+fn foreign_key_check(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+  let mut stmt = conn.prepare("PRAGMA foreign_key_check")?;
+  let violations = stmt
+    .query_map(params![], |row| {
+      Ok(format!(
+        "{} row {} references a missing {} row",
+        row.get::<_, String>(0)?,
+        row.get::<_, Option<i64>>(1)?.unwrap_or_default(),
+        row.get::<_, String>(2)?
+      ))
+    })?
+    .collect();
+  violations
+}
+
+// One line verdict for monitoring. Nothing is exported or written.
+fn health(context: &Context, max_age: u64) -> Result<(), String> {
+  let now = SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap()
+    .as_millis() as u64;
+
+  for profile in &context.profiles {
+    profile_health(profile, max_age, now).map_err(|reason| format!("profile \"{}\": {}", profile.name, reason))?;
+  }
+  Ok(())
+}
+
+fn profile_health(profile: &Profile, max_age: u64, now: u64) -> Result<(), String> {
+  profile
+    .open()
+    .map_err(|err| err.to_string())
+    .and_then(|conn| {
+      conn
+        .query_row("SELECT count(*) FROM moz_historyvisits", params![], |row| row.get::<_, i64>(0))
+        .map_err(|err| err.to_string())
+    })
+    .map_err(|err| format!("snapshot unreadable: {}", err))?;
+
+  let age = now.saturating_sub(profile.state.last_run) / 1000;
+  if profile.state.last_run == 0 {
+    return Err("never ran".to_string());
+  }
+  if age > max_age {
+    return Err(format!("last run {}s ago, more than {}s", age, max_age));
+  }
+  Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct ProfileStatus<'a> {
+  profile: &'a str,
+  // Milliseconds, 0 when never
+  last_run: u64,
+  last_sync: u64,
+  cursor: u64,
+  // Entries exported by the most recent runs, newest first
+  recent_counts: Vec<usize>,
+  healthy: bool,
+  health: String,
+}
+
+// Every profile's state and health verdict at a glance, from state.json and
+// the snapshots on disk. Nothing is exported or written.
+fn status(context: &Context, max_age: u64, json: bool) {
+  let now = SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap()
+    .as_millis() as u64;
+  let statuses: Vec<ProfileStatus> = context
+    .profiles
+    .iter()
+    .map(|profile| {
+      let health = profile_health(profile, max_age, now);
+      ProfileStatus {
+        profile: &profile.name,
+        last_run: profile.state.last_run,
+        last_sync: profile.state.last_sync,
+        cursor: profile.state.last_historyvisit_id,
+        recent_counts: profile.state.runs.iter().take(5).map(|run| run.count).collect(),
+        healthy: health.is_ok(),
+        health: health.err().unwrap_or_else(|| "healthy".to_string()),
+      }
+    })
+    .collect();
+
+  if json {
+    write_json(&statuses, std::io::stdout().lock(), true);
+    println!();
+    return;
+  }
+  println!(
+    "{:<20} {:<19} {:<19} {:>10} {:<24} health",
+    "profile", "last_run", "last_sync", "cursor", "recent_counts"
+  );
+  for status in statuses {
+    let counts: Vec<String> = status.recent_counts.iter().map(|count| count.to_string()).collect();
+    println!(
+      "{:<20} {:<19} {:<19} {:>10} {:<24} {}",
+      status.profile,
+      format_run_time(status.last_run),
+      format_run_time(status.last_sync),
+      status.cursor,
+      counts.join(","),
+      status.health
+    );
+  }
+}
+
+// A state.json timestamp in the configured timezone
+fn format_run_time(millis: u64) -> String {
+  if millis == 0 {
+    return "never".to_string();
+  }
+  let format = "%Y-%m-%d %H:%M:%S";
+  match TIMEZONE.get().copied().unwrap_or_default() {
+    Timezone::Local => Local.timestamp_millis_opt(millis as i64).unwrap().format(format).to_string(),
+    Timezone::Utc => Utc.timestamp_millis_opt(millis as i64).unwrap().format(format).to_string(),
+  }
+}
+
+fn replay(context: &Context, from_id: u64, to_id: u64) {
+  if from_id > to_id {
+    Log::error("--from-id must not be greater than --to-id").emit();
+    std::process::exit(1);
+  }
+
+  let now = SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap()
+    .as_millis();
+  for profile in &context.profiles {
+    let history = match context.on_profile_error.apply(context.profile_retries, profile, |p| {
+      let conn = p.open()?;
+      Ok(Profile::get_history_range(&conn, from_id, to_id, &context.query)?)
+    }) {
+      Some(history) => context.filter.apply(&profile.name, history),
+      None => continue,
+    };
+
+    if history.is_empty() {
+      Log::info(format!("No entries in range for profile \"{}\"", profile.name))
+        .profile(&profile.name)
+        .count(0)
+        .emit();
+      continue;
+    }
+
+    let filename = profile
+      .directory
+      .join(format!(
+        "history_replay_{}-{}_{}.json",
+        from_id,
+        to_id,
+        file_timestamp(now, context.filename_timestamp_format.as_deref())
+      ));
+    let filename = write_history_to_file(&history, &filename, &context.export);
+    Log::info(format!(
+      "Replayed {} entries to \"{}\"",
+      history.len(),
+      filename.to_string_lossy()
+    ))
+    .profile(&profile.name)
+    .count(history.len())
+    .emit();
+  }
+}
+
+// Writes top_sites_export_<timestamp>.json per profile, the `limit` places
+// with the highest frecency that pass the scheme and redaction filters
+fn top_sites(context: &mut Context, limit: usize) {
+  context.backup_places();
+  let now = SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap()
+    .as_millis();
+  let filter = &context.filter;
+  for profile in context.profiles.iter().filter(|p| !p.stalled) {
+    let sites = match context.on_profile_error.apply(context.profile_retries, profile, |p| {
+      let conn = p.open()?;
+      let mut stmt = conn.prepare(
+        "SELECT url, title, frecency, visit_count, last_visit_date FROM moz_places \
+         WHERE frecency > 0 ORDER BY frecency DESC, id",
+      )?;
+      let mut rows = stmt.query(params![])?;
+      let mut sites = vec![];
+      while sites.len() < limit {
+        let row = match rows.next()? {
+          Some(row) => row,
+          None => break,
+        };
+        let url: String = row.get(0)?;
+        if !filter.keeps_scheme(&url) {
+          continue;
+        }
+        let url = match context.query.redaction.redact(url) {
+          Some(url) => url,
+          None => continue,
+        };
+        sites.push(TopSite {
+          url,
+          title: row.get(1).unwrap_or_default(),
+          frecency: row.get(2)?,
+          visit_count: row.get(3)?,
+          last_visit_date: row.get(4)?,
+        });
+      }
+      Ok(sites)
+    }) {
+      Some(sites) => sites,
+      None => continue,
+    };
+
+    let filename = profile.directory.join(format!(
+      "top_sites_export_{}.json",
+      file_timestamp(now, context.filename_timestamp_format.as_deref())
+    ));
+    let filename = write_export(&sites, &filename, &context.export);
+    Log::info(format!("Exported {} top sites to \"{}\"", sites.len(), filename.to_string_lossy()))
+      .profile(&profile.name)
+      .count(sites.len())
+      .emit();
+  }
+}
+
+// Compares two state.json files by their cursors, or two history exports by
+// the visits only one of them holds.
+fn diff(a: &Path, b: &Path, verbose: bool) {
+  // Exits naming `path` when it can't be read or doesn't hold a `T`
+  fn parse<T: serde::de::DeserializeOwned>(path: &Path, value: serde_json::Value) -> T {
+    serde_json::from_value(value).unwrap_or_else(|err| {
+      Log::error(format!("Can't compare \"{}\": {}", path.to_string_lossy(), err)).emit();
+      std::process::exit(1);
+    })
+  }
+  let read = |path: &Path| -> serde_json::Value {
+    let value = open_json_file(path)
+      .map_err(|err| err.to_string())
+      .and_then(|file| serde_json::from_reader(file).map_err(|err| err.to_string()));
+    match value {
+      Ok(value) => strip_root_key(value),
+      Err(err) => {
+        Log::error(format!("Can't read \"{}\": {}", path.to_string_lossy(), err)).emit();
+        std::process::exit(1);
+      }
+    }
+  };
+
+  match (read(a), read(b)) {
+    (a_state @ serde_json::Value::Object(_), b_state @ serde_json::Value::Object(_)) => {
+      let (a, b): (State, State) = (parse(a, a_state), parse(b, b_state));
+      let fields = [
+        ("last_historyvisit_id", a.last_historyvisit_id, b.last_historyvisit_id),
+        ("last_sync", a.last_sync, b.last_sync),
+        ("last_run", a.last_run, b.last_run),
+        ("last_check", a.last_check, b.last_check),
+      ];
+      for (field, a, b) in fields.iter() {
+        if a != b {
+          println!("{}: {} -> {}", field, a, b);
+        }
+      }
+      if fields.iter().all(|(_, a, b)| a == b) {
+        println!("States are identical");
+      }
+    }
+    (serde_json::Value::Array(a_entries), serde_json::Value::Array(b_entries)) => {
+      let keys = |path: &Path, entries: Vec<serde_json::Value>| -> BTreeSet<(u64, String)> {
+        entries
+          .into_iter()
+          .map(|entry| {
+            let entry: HistoryEntry = parse(path, entry);
+            (entry.id, entry.url)
+          })
+          .collect()
+      };
+      let (a_keys, b_keys) = (keys(a, a_entries), keys(b, b_entries));
+      let only_a: Vec<_> = a_keys.difference(&b_keys).collect();
+      let only_b: Vec<_> = b_keys.difference(&a_keys).collect();
+
+      let max_id = |keys: &BTreeSet<(u64, String)>| keys.iter().map(|(id, _)| *id).max().unwrap_or(0);
+      println!(
+        "{} entries only in \"{}\", {} only in \"{}\"",
+        only_a.len(),
+        a.to_string_lossy(),
+        only_b.len(),
+        b.to_string_lossy()
+      );
+      println!("last visit id: {} -> {}", max_id(&a_keys), max_id(&b_keys));
+      if verbose {
+        for (id, url) in only_a {
+          println!("- {} {}", id, url);
+        }
+        for (id, url) in only_b {
+          println!("+ {} {}", id, url);
+        }
+      }
+    }
+    _ => {
+      Log::error("Both files must be state files or both history exports").emit();
+      std::process::exit(1);
+    }
+  }
+}
+
+fn main() {
+  let opt = Opt::from_args();
+  if opt.stdout || opt.tail {
+    let _ = LOGS_TO_STDERR.set(true);
+  }
+  match &opt.command {
+    Some(Command::Diff { a, b, verbose }) => {
+      diff(a, b, *verbose);
+      return;
+    }
+    Some(Command::Verify { file, public_key }) => match verify_signature(file, public_key) {
+      Ok(()) => {
+        println!("valid");
+        return;
+      }
+      Err(reason) => {
+        println!("invalid: {}", reason);
+        std::process::exit(1);
+      }
+    },
+    _ => {}
+  }
+  let source = match (&opt.from_profile_ini, &opt.snapshots_dir) {
+    (Some(_), Some(_)) => {
+      Log::error("--from-profile-ini and --snapshots-dir can't be combined").emit();
+      std::process::exit(1);
+    }
+    (Some(ini), None) => ProfileSource::ProfilesIni(ini),
+    (None, Some(snapshots)) => ProfileSource::SnapshotsDir(snapshots),
+    (None, None) => ProfileSource::Config,
+  };
+  let mut context = Context::from_config(opt.config.clone(), opt.no_state, source);
+
+  // --profile wins over FIREFOX_EXPORTER_PROFILES, a comma separated list
+  let mut requested = opt.profiles.clone();
+  if let Some(Command::ValidateDb { profiles }) = &opt.command {
+    requested.extend(profiles.iter().cloned());
+  }
+  let selected: Vec<String> = if requested.is_empty() {
+    std::env::var("FIREFOX_EXPORTER_PROFILES")
+      .unwrap_or_default()
+      .split(',')
+      .map(|name| name.trim().to_string())
+      .filter(|name| !name.is_empty())
+      .collect()
+  } else {
+    requested
+  };
+  if !selected.is_empty() {
+    for name in &selected {
+      if !context.profiles.iter().any(|p| &p.name == name) {
+        Log::error(format!("Unknown profile \"{}\"", name)).emit();
+        std::process::exit(1);
+      }
+    }
+    context.profiles.retain(|p| selected.contains(&p.name));
+  }
+
+  context.export.force = opt.force;
+  context.stdout = opt.stdout;
+  context.explain = opt.explain;
+  if opt.typed_only {
+    context.filter.only_visit_types = Some(vec![VISIT_TYPE_TYPED]);
+  }
+  context.query.search = opt.search.clone();
+  if let Some(pattern) = &opt.search_regex {
+    context.filter.search_regex = Some(Regex::new(pattern).unwrap_or_else(|err| {
+      Log::error(format!("Invalid --search-regex \"{}\": {}", pattern, err)).emit();
+      std::process::exit(1);
+    }));
+  }
+  let searching = opt.search.is_some() || opt.search_regex.is_some();
+  if let Some(fraction) = opt.sample {
+    if !(0.0..=1.0).contains(&fraction) {
+      Log::error(format!("--sample {} is not between 0.0 and 1.0", fraction)).emit();
+      std::process::exit(1);
+    }
+    let seed = opt.sample_seed.unwrap_or_else(|| {
+      SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as u64
+    });
+    context.filter.sample = Some(Sample { fraction, seed });
+  }
+
+  let since = opt.since_file.as_ref().map(|path| {
+    if context.profiles.len() != 1 {
+      Log::error("--since-file needs exactly one profile, select it with --profile").emit();
+      std::process::exit(1);
+    }
+    last_exported_id(path)
+  });
+  // Ad hoc exports leave state.json alone unless asked, so they don't skip
+  // visits for regular runs
+  let persist = (since.is_none() && !opt.typed_only && !searching) || opt.commit;
+
+  match opt.command {
+    Some(Command::Replay { from_id, to_id }) => {
+      replay(&context, from_id, to_id);
+      return;
+    }
+    Some(Command::Health { max_age }) => match health(&context, max_age) {
+      Ok(()) => {
+        println!("healthy");
+        return;
+      }
+      Err(reason) => {
+        println!("unhealthy: {}", reason);
+        std::process::exit(1);
+      }
+    },
+    Some(Command::TopSites { limit }) => {
+      top_sites(&mut context, limit);
+      return;
+    }
+    Some(Command::Count { no_backup }) => {
+      count(&mut context, since, no_backup);
+      return;
+    }
+    Some(Command::Status { max_age, json }) => {
+      status(&context, max_age, json);
+      return;
+    }
+    Some(Command::PrintSchema) => {
+      println!("{}", serde_json::to_string_pretty(&export_schema(&context)).unwrap());
+      return;
+    }
+    Some(Command::ValidateDb { .. }) => {
+      if !validate_db(&context) {
+        std::process::exit(1);
+      }
+      return;
+    }
+    Some(Command::Diff { .. }) | Some(Command::Verify { .. }) | None => {}
+  }
+
+  if opt.tail {
+    tail(&mut context, opt.tail_interval, opt.commit);
+    return;
+  }
+  if opt.preview_cursor {
+    preview_cursor(&mut context, since);
+    return;
+  }
+  if opt.catch_up {
+    let conflict = if context.query.chunk_visits.is_none() {
+      Some("--catch-up needs chunk_visits")
+    } else if since.is_some() || !persist {
+      Some("--catch-up needs the cursor saved after each chunk, not an ad hoc export")
+    } else if context.merge_profiles {
+      Some("--catch-up can't be combined with merge_profiles, which writes a single file")
+    } else if context.diff_mode {
+      Some("--catch-up can't be combined with diff_mode")
+    } else {
+      None
+    };
+    if let Some(conflict) = conflict {
+      Log::error(conflict).emit();
+      std::process::exit(1);
+    }
+  }
+
+  export(&mut context, since, persist, opt.catch_up);
+}
+
+// One export run over every profile. `since` replaces the saved cursors,
+// which are only written back with `persist`
+fn export(context: &mut Context, since: Option<u64>, persist: bool, catch_up: bool) {
+  // Pages are exported as --catch-up does chunks. Ad hoc exports don't save
+  // the cursor and read their whole range at once.
+  let checkpoint = context.checkpoint_pages && persist && since.is_none();
+  if checkpoint {
+    context.query.chunk_visits = Some(context.query.page_size as usize);
+  }
+  let catch_up = catch_up || checkpoint;
+  let mut exported: BTreeSet<String> = BTreeSet::new();
+  let strict_hook = context.post_export_strict && context.post_export_command.is_some();
+  let mut merged: Vec<HistoryEntry> = vec![];
+  let mut merged_seen: std::collections::HashSet<(String, i64)> = std::collections::HashSet::new();
+  let run_started = SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap()
+    .as_millis();
+
+  context.backup_places();
+  context.check_snapshot_freshness();
+  // --catch-up repeats the pass over the same snapshots while a profile
+  // filled its chunk
+  let mut first_pass = true;
+  loop {
+    let mut backlog = false;
+    for profile in context.profiles.iter_mut().filter(|p| !p.stalled) {
+      Log::info(format!("Getting history entries for profile \"{}\"", profile.name))
+        .profile(&profile.name)
+        .emit();
+      let started = Instant::now();
+
+      let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+      let cursor = since.unwrap_or(profile.state.last_historyvisit_id);
+      // Keywords, input history and stats don't follow the cursor, so later
+      // --catch-up passes would only write them again
+      let emit_stats = context.emit_stats && first_pass;
+      let export_keywords = context.export_keywords && first_pass;
+      let export_input_history = context.export_input_history && first_pass;
+      let query = &context.query;
+      let diff_mode = context.diff_mode;
+      let latest_per_url = context.latest_per_url;
+      let explain = context.explain;
+      let (read, deletions, keywords, input_history, stats, latest) = match context
+        .on_profile_error
+        .apply(context.profile_retries, profile, |p| {
+          let conn = p.open()?;
+          if explain {
+            p.explain_history(&conn, cursor, query)?;
+          }
+          let stats = if emit_stats { Some(p.get_stats(&conn)?) } else { None };
+          // The first diff_mode run has no previous snapshot and uses the cursor
+          let (history, deletions) = if diff_mode && p.previous_db_path().exists() {
+            p.get_history_diff(&conn, query)?
+          } else {
+            (Profile::get_history(&conn, cursor, query)?, vec![])
+          };
+          let latest = if latest_per_url { Some(Profile::get_latest_per_url(&conn, query)?) } else { None };
+          let keywords = if export_keywords { Profile::get_keywords(&conn)? } else { vec![] };
+          let input_history = if export_input_history { Profile::get_input_history(&conn)? } else { vec![] };
+          Ok((history, deletions, keywords, input_history, stats, latest))
+        }) {
+        Some(read) => read,
+        None => continue,
+      };
+      profile.state.last_run = now as u64;
+      profile.state.last_check = now as u64;
+
+      // Filtered entries are still read, so the cursor moves past them too
+      let read_to = read.read_to.max(cursor);
+      if context.query.chunk_visits.map(|n| read.read >= n).unwrap_or(false) {
+        backlog = true;
+      }
+      let mut stamp = file_timestamp(now, context.filename_timestamp_format.as_deref());
+      // Later --catch-up passes can fall in the same millisecond, or the same
+      // filename_timestamp_format period, as the first
+      if context.duplicate_filenames == DuplicateFilenames::Range || !first_pass {
+        stamp = unique_stamp(&profile.directory, &profile.name, now, &stamp, cursor, read_to);
+      }
+      let deletions = context.filter.apply(&profile.name, deletions);
+      let mut history = context.filter.apply(&profile.name, read.entries);
+      context.query.order_by.sort(&mut history);
+      let history_len = history.len();
+      let visit_types: Vec<u8> = history.iter().map(|entry| entry.visit_type).collect();
+      // Entries already counted by a per-file save
+      let mut counted = 0;
+      let mut outputs: Vec<PathBuf> = vec![];
+      let mut indexed: Vec<IndexRecord> = vec![];
+      let source_db = SourceDb::of(&profile.db_path);
+
+      if history.is_empty() {
+        // last_sync and the cursor stay put, only last_check records the run
+        Log::info("Nothing to do!").profile(&profile.name).count(0).emit();
+      } else if context.stdout {
+        stream_to_stdout(&mut std::io::stdout().lock(), &profile.name, history);
+        profile.state.last_sync = now as u64;
+        Log::info(format!("Streamed {} entries!", history_len))
+          .profile(&profile.name)
+          .count(history_len)
+          .duration(started)
+          .emit();
+      } else if let Some(output) = &context.output {
+        // Leaves before the cursor moves, so the next run sends these visits again
+        if let Err(err) = output.write(history, &profile.name) {
+          Log::error(format!("Writing to \"{}\" failed: {}", output.path().to_string_lossy(), err))
+            .profile(&profile.name)
+            .emit();
+          if context.on_output_error == OutputErrorPolicy::Fail {
+            std::process::exit(1);
+          }
+          Log::warn(format!("Skipping profile \"{}\"", profile.name))
+            .profile(&profile.name)
+            .emit();
+          continue;
+        }
+        profile.state.last_sync = now as u64;
+        Log::info(format!("Streamed {} entries to \"{}\"!", history_len, output.path().to_string_lossy()))
+          .profile(&profile.name)
+          .count(history_len)
+          .duration(started)
+          .emit();
+      } else if context.merge_profiles {
+        for mut entry in history {
+          if context.merge_dedupe && !merged_seen.insert((entry.url.clone(), entry.visit_date)) {
+            continue;
+          }
+          entry.profile = Some(profile.name.clone());
+          merged.push(entry);
+        }
+        profile.state.last_sync = now as u64;
+        Log::info(format!("Collected {} entries for the merged export", history_len))
+          .profile(&profile.name)
+          .count(history_len)
+          .duration(started)
+          .emit();
+      } else if context.gzip_log {
+        let filename = profile.directory.join("history.ndjson.gz");
+        // Exits before the cursor moves, so the next run appends these visits again
+        if let Err(err) = append_history_to_gzip_log(history, &filename) {
+          Log::error(format!("Appending to \"{}\" failed: {}", filename.to_string_lossy(), err))
+            .profile(&profile.name)
+            .emit();
+          std::process::exit(1);
+        }
+        outputs.push(filename);
+        profile.state.last_sync = now as u64;
+        Log::info(format!("Appended {} entries!", history_len))
+          .profile(&profile.name)
+          .count(history_len)
+          .duration(started)
+          .emit();
+      } else if context.one_file_per_entry {
+        // Files already written by an earlier run are left as they are
+        let mut skipped = 0;
+        for entry in &history {
+          let filename = profile.directory.join(format!("{}.json", entry.id));
+          let mut written = filename.as_os_str().to_owned();
+          written.push(context.export.compression.algo.extension());
+          if Path::new(&written).exists() {
+            skipped += 1;
+            continue;
+          }
+          outputs.push(write_export(entry, &filename, &context.export));
+        }
+        profile.state.last_sync = now as u64;
+        Log::info(format!("Exported {} entries to their own files, {} already there!", outputs.len(), skipped))
+          .profile(&profile.name)
+          .count(outputs.len())
+          .duration(started)
+          .emit();
+      } else if let Some(latest) = latest {
+        // Rewritten in full whenever there are new visits
+        let latest = context.filter.apply(&profile.name, latest);
+        let filename = profile.directory.join(format!("latest_per_url_export_{}.json", stamp));
+        outputs.push(write_export(&latest, &filename, &context.export));
+        profile.state.last_sync = now as u64;
+        Log::info(format!("Exported the latest visit of {} urls!", latest.len()))
+          .profile(&profile.name)
+          .count(latest.len())
+          .duration(started)
+          .emit();
+      } else if context.output_mode == OutputMode::Summary {
+        let summary = summarize_history(&history, context.summary_top_domains);
+        let filename = profile.directory.join(format!("summary_export_{}.json", stamp));
+        outputs.push(write_export(&summary, &filename, &context.export));
+        profile.state.last_sync = now as u64;
+        Log::info(format!("Summarized {} entries over {} days!", history.len(), summary.len()))
+          .profile(&profile.name)
+          .count(history.len())
+          .duration(started)
+          .emit();
+      } else {
+        let per_file = context
+          .max_entries_per_file
+          .filter(|n| *n > 0)
+          .unwrap_or(history.len());
+        let numbered = history.len() > per_file;
+
+        for (i, chunk) in history.chunks(per_file).enumerate() {
+          let suffix = if numbered { format!("_{}", i + 1) } else { String::new() };
+          let filename = if context.date_partitioned {
+            let directory = profile.directory.join(date_partition(now));
+            fs::create_dir_all(&directory).unwrap();
+            directory.join(format!("{}-{}{}.json", profile.name, stamp, suffix))
+          } else {
+            profile
+              .directory
+              .join(format!("history_export_{}{}.json", stamp, suffix))
+          };
+          let filename = write_history_to_file(chunk, &filename, &context.export);
+          outputs.push(filename.clone());
+          indexed.push(IndexRecord {
+            // Relative to the profile directory, partitions included
+            file: filename
+              .strip_prefix(&profile.directory)
+              .unwrap()
+              .to_string_lossy()
+              .into_owned(),
+            timestamp: now as u64,
+            from_id: chunk.iter().map(|e| e.id).min().unwrap(),
+            to_id: chunk.iter().map(|e| e.id).max().unwrap(),
+            count: chunk.len(),
+            format: "json".to_string(),
+            compression: context.export.compression.algo,
+            source_db: source_db.clone(),
+          });
+
+          if context.verify_output {
+            if let Err(err) = verify_history_file(&filename, chunk.len()) {
+              Log::error(format!(
+                "Verification of \"{}\" failed: {}",
+                filename.to_string_lossy(),
+                err
+              ))
+              .profile(&profile.name)
+              .emit();
+              std::process::exit(1);
+            }
+            Log::info(format!(
+              "Verified {} entries in \"{}\"",
+              chunk.len(),
+              filename.to_string_lossy()
+            ))
+            .profile(&profile.name)
+            .count(chunk.len())
+            .emit();
+          }
+
+          // Persist the cursor after every completed file so an interrupted
+          // run resumes after the last file written. Files needn't be in id
+          // order, so it stops short of the lowest id still to be written.
+          // The last file is saved with the run below, and a strict hook
+          // must succeed before anything is saved.
+          let written = ((i + 1) * per_file).min(history.len());
+          profile.state.last_historyvisit_id = history[written..]
+            .iter()
+            .map(|e| e.id - 1)
+            .min()
+            .unwrap_or(read_to);
+          profile.state.last_sync = now as u64;
+          if numbered && persist && written < history.len() && !strict_hook {
+            profile.state.count_exported(&visit_types[counted..written]);
+            counted = written;
+            profile.save_state();
+          }
+        }
+        Log::info(format!("Exported {} entries!", history.len()))
+          .profile(&profile.name)
+          .count(history.len())
+          .duration(started)
+          .emit();
+      }
+      exported.insert(profile.name.clone());
+      // Counted only when the state is saved, so the counters never go back
+      if persist {
+        profile.state.count_exported(&visit_types[counted..]);
+      }
+      profile.state.last_historyvisit_id = read_to;
+      profile.state.record_run(
+        RunRecord {
+          timestamp: now as u64,
+          count: history_len,
+          from_id: cursor,
+          to_id: read_to,
+          duration_ms: started.elapsed().as_millis() as u64,
+        },
+        context.runs_log_size,
+      );
+
+      if !keywords.is_empty() {
+        write_export(
+          &keywords,
+          &profile.directory.join(format!("keywords_export_{}.json", stamp)),
+          &context.export,
+        );
+        Log::info(format!("Exported {} keywords!", keywords.len()))
+          .profile(&profile.name)
+          .count(keywords.len())
+          .emit();
+      }
+
+      if !input_history.is_empty() {
+        write_export(
+          &input_history,
+          &profile.directory.join(format!("inputhistory_export_{}.json", stamp)),
+          &context.export,
+        );
+        Log::info(format!("Exported {} input history entries!", input_history.len()))
+          .profile(&profile.name)
+          .count(input_history.len())
+          .emit();
+      }
+
+      if !deletions.is_empty() {
+        write_export(
+          &deletions,
+          &profile.directory.join(format!("deletions_export_{}.json", stamp)),
+          &context.export,
+        );
+        Log::info(format!("Exported {} deleted entries!", deletions.len()))
+          .profile(&profile.name)
+          .count(deletions.len())
+          .emit();
+      }
+
+      if let Some(stats) = stats {
+        write_export(
+          &stats,
+          &profile.directory.join(format!("stats_export_{}.json", stamp)),
+          &context.export,
+        );
+      }
+
+      if context.retain_exports > 0 {
+        for path in prune_exports(&profile.directory, context.retain_exports) {
+          Log::info(format!("Removed old export \"{}\"", path.to_string_lossy()))
+            .profile(&profile.name)
+            .emit();
+        }
+      }
+      if !indexed.is_empty() || context.retain_exports > 0 {
+        update_index(&profile.directory, indexed);
+      }
+
+      let hook_command = context.post_export_command.as_deref().filter(|_| !outputs.is_empty());
+      let strict = context.post_export_strict;
+      let run_hook = |profile: &Profile| {
+        if let Some(command) = hook_command {
+          let hook = PostExportHook {
+            profile: Some(&profile.name),
+            outputs: &outputs,
+            count: history_len,
+            cursor: profile.state.last_historyvisit_id,
+          };
+          hook.run(command, strict);
+        }
+      };
+      // A strict hook runs first, so a failing one exits before the cursor
+      // is saved and the next run exports the same visits again
+      if strict {
+        run_hook(profile);
+      }
+
+      // Merged runs only persist cursors once the merged file is written
+      if persist && !context.merge_profiles {
+        profile.save_state();
+        if context.diff_mode {
+          profile.keep_previous_snapshot();
+        }
+      }
+
+      if !strict {
+        run_hook(profile);
+      }
+    }
+
+    if !(catch_up && backlog) {
+      break;
+    }
+    first_pass = false;
+  }
+
+  if context.merge_profiles {
+    if !merged.is_empty() {
+      let mut directory = context.working_directory.clone();
+      if context.date_partitioned {
+        directory.push(date_partition(run_started));
+        fs::create_dir_all(&directory).unwrap();
+      }
+      let filename = directory.join(format!(
+        "merged_history_{}.json",
+        file_timestamp(run_started, context.filename_timestamp_format.as_deref())
+      ));
+      let filename = write_history_to_file(&merged, &filename, &context.export);
+      Log::info(format!("Exported {} merged entries!", merged.len()))
+        .count(merged.len())
+        .emit();
+
+      if let Some(command) = &context.post_export_command {
+        let hook = PostExportHook {
+          profile: None,
+          outputs: &[filename],
+          count: merged.len(),
+          cursor: merged.iter().map(|e| e.id).max().unwrap_or(0),
+        };
+        hook.run(command, context.post_export_strict);
+      }
+    }
+
+    if persist {
+      for profile in context.profiles.iter_mut().filter(|p| exported.contains(&p.name)) {
+        profile.save_state();
+        if context.diff_mode {
+          profile.keep_previous_snapshot();
+        }
+      }
+    }
+  }
+
+  if let Some(path) = &context.textfile_path {
+    write_textfile_metrics(path, &context.profiles);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A working directory and a Firefox profile "alpha" in a fresh temporary
+  // directory, removed again when dropped
+  struct Fixture {
+    root: PathBuf,
+  }
+
+  impl Fixture {
+    fn new(name: &str, config: &str) -> Fixture {
+      let root = std::env::temp_dir().join(format!("firefox-exporter-{}-{}", name, std::process::id()));
+      let _ = fs::remove_dir_all(&root);
+      fs::create_dir_all(root.join("ff")).unwrap();
+      fs::create_dir_all(root.join("wd/profiles/alpha")).unwrap();
+      fs::write(
+        root.join("config.toml"),
+        format!(
+          "working_directory = \"wd\"\n{}\n[profile.alpha]\nfirefox_path = \"ff\"\n",
+          config
+        ),
+      )
+      .unwrap();
+
+      let conn = Connection::open(root.join("ff/places.sqlite")).unwrap();
+      conn
+        .execute_batch(
+          "CREATE TABLE moz_places (id INTEGER PRIMARY KEY, url LONGVARCHAR, title LONGVARCHAR, \
+             visit_count INTEGER DEFAULT 0, frecency INTEGER DEFAULT -1 NOT NULL, last_visit_date INTEGER);
+           CREATE TABLE moz_historyvisits (id INTEGER PRIMARY KEY, from_visit INTEGER, place_id INTEGER, \
+             visit_date INTEGER, visit_type INTEGER);
+           INSERT INTO moz_places (id, url, title) VALUES (1, 'https://example.com/', 'Example');
+           INSERT INTO moz_places (id, url, title) VALUES (2, 'https://rust-lang.org/', 'Rust');",
+        )
+        .unwrap();
+      Fixture { root }
+    }
+
+    fn places(&self) -> Connection {
+      Connection::open(self.root.join("ff/places.sqlite")).unwrap()
+    }
+
+    // Adds visits with the next `count` ids, an hour apart
+    fn add_visits(&self, count: usize) {
+      let mut conn = self.places();
+      let transaction = conn.transaction().unwrap();
+      let last: i64 = transaction
+        .query_row("SELECT coalesce(max(id), 0) FROM moz_historyvisits", params![], |row| row.get(0))
+        .unwrap();
+      for id in last + 1..=last + count as i64 {
+        transaction
+          .execute(
+            "INSERT INTO moz_historyvisits (id, place_id, visit_date, visit_type) VALUES (?, ?, ?, 1)",
+            params![id, id % 2 + 1, 1_600_000_000_000_000 + id * 3_600_000_000],
+          )
+          .unwrap();
+      }
+      transaction.commit().unwrap();
+    }
+
+    fn context(&self) -> Context {
+      Context::from_config(self.root.join("config.toml"), false, ProfileSource::Config)
+    }
+
+    fn directory(&self) -> PathBuf {
+      self.root.join("wd/profiles/alpha")
+    }
+
+    // Export files starting with `prefix`, by name
+    fn exports(&self, prefix: &str) -> Vec<PathBuf> {
+      let mut files: Vec<PathBuf> = fs::read_dir(self.directory())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with(prefix))
+        .collect();
+      files.sort();
+      files
+    }
+
+    fn state(&self) -> State {
+      State::from_json(&self.directory().join("state.json"))
+    }
+  }
+
+  // Runs the #[ignore]d test `name` in a child process for code paths that
+  // exit, returning its exit code. The child works on `directory` and does
+  // nothing when run without it.
+  fn exit_code_of(name: &str, directory: &Path) -> Option<i32> {
+    child(name, directory).status().unwrap().code()
+  }
+
+  fn child(name: &str, directory: &Path) -> std::process::Command {
+    let mut command = std::process::Command::new(std::env::current_exe().unwrap());
+    command
+      .args([name, "--exact", "--ignored", "--nocapture"])
+      .env("FIREFOX_EXPORTER_TEST_DIR", directory)
+      .stdout(std::process::Stdio::null())
+      .stderr(std::process::Stdio::null());
+    command
+  }
+
+  fn child_directory() -> Option<PathBuf> {
+    std::env::var_os("FIREFOX_EXPORTER_TEST_DIR").map(PathBuf::from)
+  }
+
+  impl Drop for Fixture {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.root);
+    }
+  }
+
+  #[test]
+  fn max_entries_per_file_splits_exports() {
+    let fixture = Fixture::new("max-entries", "max_entries_per_file = 100");
+    fixture.add_visits(250);
+    export(&mut fixture.context(), None, true, false);
+
+    let files = fixture.exports("history_export_");
+    assert_eq!(files.len(), 3);
+    let counts: Vec<usize> = files.iter().map(|file| read_history_file(file).unwrap().len()).collect();
+    assert_eq!(counts, vec![100, 100, 50]);
+    assert!(files.iter().all(|file| file.to_string_lossy().ends_with(".json")));
+    let state = fixture.state();
+    assert_eq!(state.last_historyvisit_id, 250);
+    assert_eq!(state.exported_total, 250);
+    assert_eq!(state.runs.len(), 1);
+  }
+
+  #[test]
+  fn max_entries_per_file_keeps_small_exports_whole() {
+    let fixture = Fixture::new("max-entries-small", "max_entries_per_file = 100");
+    fixture.add_visits(100);
+    export(&mut fixture.context(), None, true, false);
+
+    let files = fixture.exports("history_export_");
+    assert_eq!(files.len(), 1);
+    assert!(!files[0].to_string_lossy().ends_with("_1.json"));
+  }
+
+  #[test]
+  fn keywords_are_only_exported_when_asked() {
+    for (config, expected) in [("", 0), ("export_keywords = true", 1)] {
+      let fixture = Fixture::new("keywords", config);
+      fixture
+        .places()
+        .execute_batch(
+          "CREATE TABLE moz_keywords (id INTEGER PRIMARY KEY, keyword TEXT UNIQUE, place_id INTEGER, post_data TEXT);
+           INSERT INTO moz_keywords (keyword, place_id) VALUES ('rs', 2);",
+        )
+        .unwrap();
+      fixture.add_visits(1);
+      export(&mut fixture.context(), None, true, false);
+      assert_eq!(fixture.exports("keywords_export_").len(), expected);
+    }
+  }
+
+  // A profile whose snapshot is not a database, read with `policy`
+  fn broken_profile(name: &str, policy: &str) -> (Fixture, Context) {
+    let fixture = Fixture::new(name, &format!("on_profile_error = \"{}\"\nprofile_retries = 2", policy));
+    fs::write(fixture.directory().join("places.sqlite"), "not a database").unwrap();
+    let context = fixture.context();
+    (fixture, context)
+  }
+
+  // Reads the broken profile, returning what apply gave back and how often
+  // it tried
+  fn read_broken(context: &Context) -> (Option<Vec<HistoryEntry>>, u32) {
+    let attempts = std::cell::Cell::new(0);
+    let read = context.on_profile_error.apply(context.profile_retries, &context.profiles[0], |p| {
+      attempts.set(attempts.get() + 1);
+      let conn = p.open()?;
+      Ok(Profile::get_history(&conn, 0, &context.query)?.entries)
+    });
+    (read, attempts.get())
+  }
+
+  #[test]
+  fn skip_policy_skips_a_broken_profile() {
+    let (_fixture, context) = broken_profile("policy-skip", "skip");
+    let (read, attempts) = read_broken(&context);
+    assert!(read.is_none());
+    assert_eq!(attempts, 1);
+  }
+
+  #[test]
+  fn retry_policy_retries_a_broken_profile_then_skips_it() {
+    let (_fixture, context) = broken_profile("policy-retry", "retry");
+    let (read, attempts) = read_broken(&context);
+    assert!(read.is_none());
+    assert_eq!(attempts, 2);
+  }
+
+  #[test]
+  #[ignore]
+  fn fail_policy_child() {
+    if let Some(root) = child_directory() {
+      read_broken(&Context::from_config(root.join("config.toml"), false, ProfileSource::Config));
+    }
+  }
+
+  #[test]
+  fn fail_policy_exits_on_a_broken_profile() {
+    let (fixture, _) = broken_profile("policy-fail", "fail");
+    assert_eq!(exit_code_of("tests::fail_policy_child", &fixture.root), Some(1));
+  }
+
+  #[test]
+  fn relative_ini_paths_join_on_both_separators() {
+    let expected = Path::new("root").join("Profiles").join("abc.default");
+    for path in &["Profiles/abc.default", "Profiles\\abc.default"] {
+      let profile = IniProfile {
+        name: "default".to_string(),
+        path: path.to_string(),
+        is_relative: true,
+        default: true,
+      };
+      assert_eq!(profile.directory(Path::new("root")), expected);
+    }
+  }
+
+  #[test]
+  fn profile_paths_are_joined_under_the_working_directory() {
+    let fixture = Fixture::new("paths", "");
+    let context = fixture.context();
+    let profile = &context.profiles[0];
+    let directory = fixture.root.join("wd").join("profiles").join("alpha");
+    assert_eq!(profile.directory, directory);
+    assert_eq!(profile.db_path, directory.join("places.sqlite"));
+    assert_eq!(profile.path, fixture.root.join("ff"));
+  }
+
+  #[test]
+  fn redacted_visits_are_dropped_when_read_but_still_move_the_cursor() {
+    let fixture = Fixture::new("redact-drop", "redact_url_patterns = [\"rust-lang\"]");
+    fixture.add_visits(4);
+    let context = fixture.context();
+    let history = Profile::get_history(&fixture.places(), 0, &context.query).unwrap();
+    assert_eq!(history.read, 4);
+    assert_eq!(history.read_to, 4);
+    let ids: Vec<u64> = history.entries.iter().map(|e| e.id).collect();
+    assert_eq!(ids, vec![2, 4]);
+
+    export(&mut fixture.context(), None, true, false);
+    assert_eq!(read_history_file(&fixture.exports("history_export_")[0]).unwrap().len(), 2);
+    assert_eq!(fixture.state().last_historyvisit_id, 4);
+  }
+
+  #[test]
+  fn redacted_urls_are_masked_when_read() {
+    let fixture = Fixture::new("redact-mask", "redact_url_patterns = [\"rust-lang\"]\nredact_action = \"mask\"");
+    fixture.add_visits(2);
+    let context = fixture.context();
+    let history = Profile::get_history(&fixture.places(), 0, &context.query).unwrap();
+    let urls: Vec<&str> = history.entries.iter().map(|e| e.url.as_str()).collect();
+    assert_eq!(urls, vec!["https://***.org/", "https://example.com/"]);
+  }
+
+  #[test]
+  fn label_values_are_escaped() {
+    assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    assert_eq!(escape_label("alpha"), "alpha");
+  }
+
+  #[test]
+  fn exported_total_counts_every_saved_run() {
+    let fixture = Fixture::new("metrics", "textfile_path = \"metrics.prom\"");
+    fixture.add_visits(3);
+    export(&mut fixture.context(), None, true, false);
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    fixture.add_visits(2);
+    export(&mut fixture.context(), None, true, false);
+
+    let metrics = fs::read_to_string(fixture.root.join("metrics.prom")).unwrap();
+    assert!(metrics.contains("# TYPE firefox_history_exported_total counter\n"));
+    assert!(metrics.contains("firefox_history_exported_total{profile=\"alpha\"} 5\n"));
+    assert!(metrics.contains("# TYPE firefox_history_visits_by_type_total counter\n"));
+    assert!(metrics.contains("firefox_history_visits_by_type_total{profile=\"alpha\",type=\"link\"} 5\n"));
+    assert_eq!(fixture.state().exported_total, 5);
+  }
+
+  // Size and time of a snapshot taken with fs::copy and with VACUUM INTO,
+  // of a database with half its visits deleted. Run with
+  // cargo test --release -- --ignored snapshot_benchmark --nocapture
+  #[test]
+  #[ignore]
+  fn snapshot_benchmark() {
+    let fixture = Fixture::new("snapshot-benchmark", "");
+    fixture.add_visits(500_000);
+    let conn = fixture.places();
+    conn.execute("DELETE FROM moz_historyvisits WHERE id % 2 = 0", params![]).unwrap();
+
+    let source = fixture.root.join("ff/places.sqlite");
+    let copied = fixture.root.join("copied.sqlite");
+    let started = Instant::now();
+    fs::copy(&source, &copied).unwrap();
+    let copy_time = started.elapsed();
+
+    let vacuumed = fixture.root.join("vacuumed.sqlite");
+    let started = Instant::now();
+    vacuum_into(&conn, &vacuumed).unwrap();
+    let vacuum_time = started.elapsed();
+
+    let copy_size = fs::metadata(&copied).unwrap().len();
+    let vacuum_size = fs::metadata(&vacuumed).unwrap().len();
+    println!("fs::copy    {:>10} bytes in {:?}", copy_size, copy_time);
+    println!("VACUUM INTO {:>10} bytes in {:?}", vacuum_size, vacuum_time);
+    assert!(vacuum_size < copy_size);
+  }
+
+  #[test]
+  fn database_filename_is_used_for_the_source_and_the_snapshot() {
+    let fixture = Fixture::new("database-filename", "database_filename = \"renamed.sqlite\"");
+    fixture.add_visits(3);
+    fs::rename(fixture.root.join("ff/places.sqlite"), fixture.root.join("ff/renamed.sqlite")).unwrap();
+    let mut context = fixture.context();
+    assert_eq!(context.profiles[0].db_path, fixture.directory().join("renamed.sqlite"));
+
+    export(&mut context, None, true, false);
+    assert!(fixture.directory().join("renamed.sqlite").exists());
+    assert!(!fixture.directory().join("places.sqlite").exists());
+    assert_eq!(read_history_file(&fixture.exports("history_export_")[0]).unwrap().len(), 3);
+  }
+
+  #[test]
+  fn dwell_ms_follows_visit_dates_while_entries_stay_in_id_order() {
+    let fixture = Fixture::new("dwell", "");
+    fixture
+      .places()
+      .execute_batch(
+        "INSERT INTO moz_historyvisits (id, place_id, visit_date, visit_type) VALUES (1, 1, 1600000002000000, 1);
+         INSERT INTO moz_historyvisits (id, place_id, visit_date, visit_type) VALUES (2, 2, 1600000000000000, 1);
+         INSERT INTO moz_historyvisits (id, place_id, visit_date, visit_type) VALUES (3, 1, 1600000005000000, 1);",
+      )
+      .unwrap();
+    let context = fixture.context();
+    let history = Profile::get_history(&fixture.places(), 0, &context.query).unwrap().entries;
+    let ids: Vec<u64> = history.iter().map(|e| e.id).collect();
+    let dwell: Vec<Option<i64>> = history.iter().map(|e| e.dwell_ms).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert_eq!(dwell, vec![Some(3000), Some(2000), None]);
+  }
+
+  // Formatting 500k visit dates in the local zone and with the UTC fast
+  // path. Run with
+  // cargo test --release -- --ignored date_formatting_benchmark --nocapture
+  #[test]
+  #[ignore]
+  fn date_formatting_benchmark() {
+    for timezone in [Timezone::Local, Timezone::Utc] {
+      let started = Instant::now();
+      let mut length = 0;
+      for i in 0..500_000i64 {
+        length += format_visit_date_in(timezone, 1_600_000_000_000_000 + i * 60_000_000, None).len();
+      }
+      println!("{:?} {:?} ({} bytes)", timezone, started.elapsed(), length);
+    }
+  }
+
+  fn export_options(force: bool) -> ExportOptions {
+    ExportOptions {
+      pretty: false,
+      force,
+      compression: Compression::default(),
+      signing_key: None,
+      root_key: None,
+    }
+  }
+
+  #[test]
+  #[ignore]
+  fn export_collision_child() {
+    if let Some(root) = child_directory() {
+      write_export(&[4], &root.join("export.json"), &export_options(false));
+    }
+  }
+
+  #[test]
+  fn existing_exports_are_only_overwritten_with_force() {
+    let fixture = Fixture::new("collision", "");
+    let filename = fixture.root.join("export.json");
+    write_export(&[1, 2, 3], &filename, &export_options(false));
+
+    assert_eq!(exit_code_of("tests::export_collision_child", &fixture.root), Some(1));
+    assert_eq!(fs::read_to_string(&filename).unwrap(), "[1,2,3]");
+
+    // A shorter export leaves nothing of the longer one behind
+    write_export(&[4], &filename, &export_options(true));
+    assert_eq!(fs::read_to_string(&filename).unwrap(), "[4]");
+  }
+
+  #[test]
+  #[ignore]
+  fn diff_child() {
+    if let Some(root) = child_directory() {
+      diff(&root.join("a.json"), &root.join("b.json"), false);
+    }
+  }
+
+  #[test]
+  fn diff_exits_cleanly_on_unreadable_files() {
+    let fixture = Fixture::new("diff", "");
+    let state = "{\"last_run\": 1, \"last_sync\": 1, \"last_historyvisit_id\": 1}";
+    fs::write(fixture.root.join("a.json"), state).unwrap();
+    assert_eq!(exit_code_of("tests::diff_child", &fixture.root), Some(1), "missing file");
+
+    for b in &["{\"last_run\": ", "{\"cursor\": 1}", "[1, 2]", "[]"] {
+      fs::write(fixture.root.join("b.json"), b).unwrap();
+      assert_eq!(exit_code_of("tests::diff_child", &fixture.root), Some(1), "{}", b);
+    }
+
+    fs::write(fixture.root.join("b.json"), state).unwrap();
+    assert_eq!(exit_code_of("tests::diff_child", &fixture.root), Some(0));
+  }
+
+  #[test]
+  fn microsecond_visit_dates_are_kept() {
+    assert_eq!(TimestampUnit::Us.micros(1_600_000_000_000_000), 1_600_000_000_000_000);
+    assert_eq!(TimestampUnit::Us.micros(1_600_000_000_000), 1_600_000_000_000);
+  }
+
+  #[test]
+  fn millisecond_visit_dates_are_scaled() {
+    assert_eq!(TimestampUnit::Ms.micros(1_600_000_000_000), 1_600_000_000_000_000);
+    assert_eq!(TimestampUnit::Ms.micros(0), 0);
+  }
+
+  #[test]
+  fn auto_detects_the_unit_at_1e14() {
+    // 1e14 microseconds is early 1973, 1e14 milliseconds far in the future
+    assert_eq!(TimestampUnit::Auto.micros(99_999_999_999_999), 99_999_999_999_999_000);
+    assert_eq!(TimestampUnit::Auto.micros(100_000_000_000_000), 100_000_000_000_000);
+    assert_eq!(TimestampUnit::Auto.micros(1_600_000_000_000), 1_600_000_000_000_000);
+    assert_eq!(TimestampUnit::Auto.micros(1_600_000_000_000_000), 1_600_000_000_000_000);
+    assert_eq!(TimestampUnit::Auto.micros(-1_600_000_000_000), -1_600_000_000_000_000);
+  }
+
+  #[test]
+  fn hook_output_files_are_one_per_line() {
+    let outputs = vec![PathBuf::from("/a:b/history_export_1_1.json"), PathBuf::from("/a:b/history_export_1_2.json")];
+    let hook = PostExportHook { profile: None, outputs: &outputs, count: 2, cursor: 2 };
+    assert_eq!(
+      hook.output_files(),
+      std::ffi::OsString::from("/a:b/history_export_1_1.json\n/a:b/history_export_1_2.json")
+    );
+  }
+
+  #[test]
+  #[ignore]
+  fn export_child() {
+    if let Some(root) = child_directory() {
+      let mut context = Context::from_config(root.join("config.toml"), false, ProfileSource::Config);
+      export(&mut context, None, true, false);
+    }
+  }
+
+  #[test]
+  fn failing_strict_hook_keeps_the_cursor() {
+    let fixture = Fixture::new("strict-hook", "post_export_command = \"exit 3\"\npost_export_strict = true");
+    fixture.add_visits(2);
+    assert_eq!(exit_code_of("tests::export_child", &fixture.root), Some(1));
+    assert_eq!(fixture.exports("history_export_").len(), 1);
+    assert_eq!(fixture.state().last_historyvisit_id, 0);
+  }
+
+  #[test]
+  fn failing_strict_hook_keeps_the_cursor_with_several_files() {
+    let fixture = Fixture::new(
+      "strict-hook-files",
+      "post_export_command = \"exit 3\"\npost_export_strict = true\nmax_entries_per_file = 2",
+    );
+    fixture.add_visits(5);
+    assert_eq!(exit_code_of("tests::export_child", &fixture.root), Some(1));
+    assert_eq!(fixture.exports("history_export_").len(), 3);
+    let state = fixture.state();
+    assert_eq!(state.last_historyvisit_id, 0);
+    assert_eq!(state.exported_total, 0);
+    assert!(state.runs.is_empty());
+  }
+
+  #[test]
+  fn hook_sees_the_written_exports() {
+    let fixture = Fixture::new(
+      "hook",
+      r#"post_export_command = "printf '%s %s' \"$FE_COUNT\" \"$FE_OUTPUT_FILE\" > \"$(dirname \"$FE_OUTPUT_FILE\")/hook.txt\"""#,
+    );
+    fixture.add_visits(2);
+    let mut context = fixture.context();
+    export(&mut context, None, true, false);
+    let file = fixture.exports("history_export_").remove(0);
+    let seen = fs::read_to_string(fixture.directory().join("hook.txt")).unwrap();
+    assert_eq!(seen, format!("2 {}", file.to_string_lossy()));
+    assert_eq!(fixture.state().last_historyvisit_id, 2);
+  }
+
+  #[test]
+  fn working_directory_must_be_outside_the_profile() {
+    let fixture = Fixture::new("outside-profile", "");
+    let live = fixture.root.join("ff");
+    fs::create_dir_all(live.join("exports")).unwrap();
+    fs::create_dir_all(fixture.root.join("ff2")).unwrap();
+    assert!(check_outside_profile(&live, &live).is_err());
+    assert!(check_outside_profile(&live, &live.join("exports")).is_err());
+    assert!(check_outside_profile(&live, &live.join("exports/..")).is_err());
+    assert!(check_outside_profile(&live, &fixture.root.join("ff2")).is_ok());
+    assert!(check_outside_profile(&live, &fixture.directory()).is_ok());
+  }
+
+  #[test]
+  fn on_bad_timestamp_handles_zero_and_negative_visit_dates() {
+    let good = 1_600_000_000_000_000;
+    for (policy, expected) in [
+      ("skip", vec![(1, good)]),
+      ("zero-epoch", vec![(1, good), (2, 0), (3, 0)]),
+      ("keep-raw", vec![(1, good), (2, 0), (3, -5)]),
+    ] {
+      let fixture = Fixture::new("bad-timestamp", &format!("on_bad_timestamp = \"{}\"", policy));
+      fixture
+        .places()
+        .execute_batch(&format!(
+          "INSERT INTO moz_historyvisits (id, place_id, visit_date, visit_type) VALUES (1, 1, {}, 1), \
+             (2, 1, 0, 1), (3, 2, -5, 1);",
+          good
+        ))
+        .unwrap();
+      export(&mut fixture.context(), None, true, false);
+
+      let history = read_history_file(&fixture.exports("history_export_")[0]).unwrap();
+      let mut dates: Vec<(u64, i64)> = history.iter().map(|entry| (entry.id, entry.visit_date)).collect();
+      dates.sort();
+      assert_eq!(dates, expected, "{}", policy);
+      if policy == "zero-epoch" {
+        assert!(history.iter().filter(|entry| entry.id > 1).all(|entry| entry.date == format_visit_date(0, None)));
+      }
+      assert_eq!(fixture.state().last_historyvisit_id, 3);
+    }
+  }
+
+  // Fails every write with an error of its kind
+  struct FailingWriter(std::io::ErrorKind);
+
+  impl Write for FailingWriter {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+      Err(std::io::Error::new(self.0, "failing writer"))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  #[ignore]
+  fn stdout_child() {
+    if let Some(directory) = child_directory() {
+      let kind = match directory.file_name().unwrap().to_str().unwrap() {
+        "broken-pipe" => std::io::ErrorKind::BrokenPipe,
+        _ => std::io::ErrorKind::Other,
+      };
+      let fixture = Fixture::new("stdout-entries", "");
+      fixture.add_visits(1);
+      let entries = Profile::get_history_range(&fixture.places(), 1, 1, &fixture.context().query).unwrap();
+      stream_to_stdout(&mut FailingWriter(kind), "alpha", entries);
+      std::process::exit(2);
+    }
+  }
+
+  #[test]
+  fn stdout_stops_cleanly_on_a_broken_pipe_only() {
+    assert_eq!(exit_code_of("tests::stdout_child", Path::new("broken-pipe")), Some(0));
+    assert_eq!(exit_code_of("tests::stdout_child", Path::new("other")), Some(1));
+  }
+
+  #[test]
+  fn idle_runs_only_move_last_run_and_last_check() {
+    let fixture = Fixture::new("idle-run", "");
+    fixture.add_visits(2);
+    export(&mut fixture.context(), None, true, false);
+    let first = fixture.state();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    export(&mut fixture.context(), None, true, false);
+
+    let second = fixture.state();
+    assert!(second.last_run > first.last_run);
+    assert!(second.last_check > first.last_check);
+    assert_eq!(second.last_sync, first.last_sync);
+    assert_eq!(second.last_historyvisit_id, 2);
+    assert_eq!(fixture.exports("history_export_").len(), 1);
+  }
+
+  #[test]
+  fn check_writable_probes_the_directory() {
+    let fixture = Fixture::new("writable", "");
+    assert!(check_writable(&fixture.directory()).is_ok());
+    assert!(!fixture.directory().join(".writetest").exists());
+    assert!(check_writable(&fixture.root.join("missing")).is_err());
+    // A file where the directory should be can't hold the probe either
+    assert!(check_writable(&fixture.root.join("config.toml")).is_err());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn check_writable_rejects_a_read_only_directory() {
+    use std::os::unix::fs::PermissionsExt;
+    let fixture = Fixture::new("read-only", "");
+    let directory = fixture.directory();
+    fs::set_permissions(&directory, fs::Permissions::from_mode(0o555)).unwrap();
+    // Root writes through the mode bits, so there's nothing to check then
+    let writable = fs::File::create(directory.join("probe")).is_ok();
+    if !writable {
+      assert!(check_writable(&directory).is_err());
+    }
+    fs::set_permissions(&directory, fs::Permissions::from_mode(0o755)).unwrap();
+  }
+
+  #[test]
+  fn snapshots_pick_up_uncheckpointed_wal_writes() {
+    let fixture = Fixture::new("wal", "");
+    // Without autocheckpoint, the visits stay in the -wal file for as long
+    // as this connection is open
+    let conn = fixture.places();
+    conn.query_row("PRAGMA journal_mode = WAL", params![], |_| Ok(())).unwrap();
+    conn
+      .execute_batch(
+        "PRAGMA wal_autocheckpoint = 0;
+         INSERT INTO moz_historyvisits (id, place_id, visit_date, visit_type) VALUES \
+           (1, 1, 1600000000000000, 1), (2, 2, 1600000001000000, 1), (3, 1, 1600000002000000, 1);",
+      )
+      .unwrap();
+    let source = fixture.root.join("ff/places.sqlite");
+    assert!(has_pending_wal(&source));
+
+    // A -wal left next to the snapshot by an earlier copy is not replayed
+    fs::write(sidecar_path(&fixture.directory().join("places.sqlite"), "-wal"), "stale").unwrap();
+    export(&mut fixture.context(), None, true, false);
+    let history = read_history_file(&fixture.exports("history_export_")[0]).unwrap();
+    assert_eq!(history.len(), 3);
+    assert_eq!(fixture.state().last_historyvisit_id, 3);
+    drop(conn);
+  }
+
+  // Visit 1 has a place, 2 and 3 point at a place that's gone
+  fn dangling_place_fixture(policy: &str) -> Fixture {
+    let fixture = Fixture::new(&format!("missing-place-{}", policy), &format!("on_missing_place = \"{}\"", policy));
+    fixture
+      .places()
+      .execute_batch(
+        "INSERT INTO moz_historyvisits (id, place_id, visit_date, visit_type) VALUES \
+           (1, 1, 1600000000000000, 1), (2, 9, 1600000001000000, 1), (3, 9, 1600000002000000, 1);",
+      )
+      .unwrap();
+    fixture
+  }
+
+  #[test]
+  fn missing_places_are_skipped_or_kept_empty() {
+    let fixture = dangling_place_fixture("skip");
+    let history = Profile::get_history_range(&fixture.places(), 1, 3, &fixture.context().query).unwrap();
+    assert_eq!(history.iter().map(|entry| entry.id).collect::<Vec<_>>(), vec![1]);
+
+    let fixture = dangling_place_fixture("keep");
+    let history = Profile::get_history_range(&fixture.places(), 1, 3, &fixture.context().query).unwrap();
+    let urls: Vec<(u64, &str)> = history.iter().map(|entry| (entry.id, entry.url.as_str())).collect();
+    assert_eq!(urls, vec![(1, "https://example.com/"), (2, ""), (3, "")]);
+  }
+
+  #[test]
+  fn missing_place_fail_names_the_dangling_visit() {
+    let fixture = dangling_place_fixture("fail");
+    let err = Profile::get_history_range(&fixture.places(), 1, 3, &fixture.context().query).unwrap_err();
+    assert_eq!(err.to_string(), "visit 2 points at place 9, which is missing from moz_places");
+  }
+
+  #[test]
+  fn gzip_log_appends_members_that_read_back_as_one_stream() {
+    let fixture = Fixture::new("gzip-log", "gzip_log = true");
+    fixture.add_visits(2);
+    export(&mut fixture.context(), None, true, false);
+    fixture.add_visits(1);
+    export(&mut fixture.context(), None, true, false);
+
+    let file = fs::File::open(fixture.directory().join("history.ndjson.gz")).unwrap();
+    let mut text = String::new();
+    flate2::read::MultiGzDecoder::new(file).read_to_string(&mut text).unwrap();
+    let ids: Vec<u64> = text
+      .lines()
+      .map(|line| serde_json::from_str::<HistoryEntry>(line).unwrap().id)
+      .collect();
+    assert_eq!(ids.len(), 3);
+    assert_eq!(ids.iter().copied().collect::<BTreeSet<_>>(), (1..=3).collect());
+    assert_eq!(fixture.state().last_historyvisit_id, 3);
+  }
+
+  #[test]
+  #[ignore]
+  fn stdin_config_child() {
+    if child_directory().is_some() {
+      let mut context = Context::from_config(PathBuf::from("-"), false, ProfileSource::Config);
+      export(&mut context, None, true, false);
+    }
+  }
+
+  #[test]
+  fn config_is_read_from_stdin_with_paths_relative_to_the_current_directory() {
+    let fixture = Fixture::new("stdin-config", "");
+    fixture.add_visits(2);
+    let config = fs::read_to_string(fixture.root.join("config.toml")).unwrap();
+    fs::remove_file(fixture.root.join("config.toml")).unwrap();
+
+    let mut command = child("tests::stdin_config_child", &fixture.root);
+    let mut process = command
+      .current_dir(&fixture.root)
+      .stdin(std::process::Stdio::piped())
+      .spawn()
+      .unwrap();
+    process.stdin.take().unwrap().write_all(config.as_bytes()).unwrap();
+    assert_eq!(process.wait().unwrap().code(), Some(0));
+    assert_eq!(fixture.exports("history_export_").len(), 1);
+    assert_eq!(fixture.state().last_historyvisit_id, 2);
+  }
+
+  #[test]
+  fn catch_up_moves_the_cursor_chunk_by_chunk_and_writes_side_exports_once() {
+    // A coarse stamp gives every pass the same timestamp
+    let fixture = Fixture::new(
+      "catch-up",
+      "chunk_visits = 100\nfilename_timestamp_format = \"%Y\"\nemit_stats = true\nexport_keywords = true",
+    );
+    fixture
+      .places()
+      .execute_batch(
+        "CREATE TABLE moz_keywords (id INTEGER PRIMARY KEY, keyword TEXT UNIQUE, place_id INTEGER, post_data TEXT);
+         INSERT INTO moz_keywords (keyword, place_id) VALUES ('rs', 2);",
+      )
+      .unwrap();
+    fixture.add_visits(250);
+    export(&mut fixture.context(), None, true, true);
+
+    let files = fixture.exports("history_export_");
+    assert_eq!(files.len(), 3);
+    let mut ids: Vec<u64> = files
+      .iter()
+      .flat_map(|file| read_history_file(file).unwrap())
+      .map(|entry| entry.id)
+      .collect();
+    ids.sort();
+    assert_eq!(ids, (1..=250).collect::<Vec<_>>());
+    assert_eq!(fixture.exports("keywords_export_").len(), 1);
+    assert_eq!(fixture.exports("stats_export_").len(), 1);
+
+    let state = fixture.state();
+    assert_eq!(state.last_historyvisit_id, 250);
+    let ranges: Vec<(u64, u64)> = state.runs.iter().rev().map(|run| (run.from_id, run.to_id)).collect();
+    assert_eq!(ranges, vec![(0, 100), (100, 200), (200, 250)]);
+  }
+
+  // Visits whose dates are out of id order
+  fn shuffled_dates_fixture(name: &str, config: &str) -> Fixture {
+    let fixture = Fixture::new(name, config);
+    fixture
+      .places()
+      .execute_batch(
+        "INSERT INTO moz_historyvisits (id, place_id, visit_date, visit_type) VALUES \
+           (1, 1, 1600000010000000, 1), (2, 2, 1600000040000000, 1), \
+           (3, 1, 1600000020000000, 1), (4, 2, 1600000030000000, 1);",
+      )
+      .unwrap();
+    fixture
+  }
+
+  fn exported_ids(file: &Path) -> Vec<u64> {
+    read_history_file(file).unwrap().iter().map(|entry| entry.id).collect()
+  }
+
+  #[test]
+  fn order_by_defaults_to_id_and_sorts_by_date_when_asked() {
+    for (config, expected) in [
+      ("", vec![1, 2, 3, 4]),
+      ("order_by = \"id\"", vec![1, 2, 3, 4]),
+      ("order_by = \"visit_date_asc\"", vec![1, 3, 4, 2]),
+      ("order_by = \"visit_date_desc\"", vec![2, 4, 3, 1]),
+    ] {
+      let fixture = shuffled_dates_fixture("order-by", config);
+      export(&mut fixture.context(), None, true, false);
+      assert_eq!(exported_ids(&fixture.exports("history_export_")[0]), expected, "{}", config);
+      assert_eq!(fixture.state().last_historyvisit_id, 4);
+    }
+  }
+
+  #[test]
+  fn visit_date_desc_still_moves_the_cursor_to_the_highest_id() {
+    let fixture = shuffled_dates_fixture("order-by-chunks", "order_by = \"visit_date_desc\"\nchunk_visits = 3");
+    export(&mut fixture.context(), None, true, false);
+    assert_eq!(exported_ids(&fixture.exports("history_export_")[0]), vec![2, 3, 1]);
+    assert_eq!(fixture.state().last_historyvisit_id, 3);
+
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    export(&mut fixture.context(), None, true, false);
+    assert_eq!(exported_ids(&fixture.exports("history_export_")[1]), vec![4]);
+    assert_eq!(fixture.state().last_historyvisit_id, 4);
+  }
+
+  #[test]
+  fn unreadable_state_falls_back_to_its_backup() {
+    let fixture = Fixture::new("state-backup", "");
+    let path = fixture.directory().join("state.json");
+    fs::write(&path, "{").unwrap();
+    let backup = State { last_historyvisit_id: 7, ..Default::default() };
+    fs::write(State::backup_path(&path), serde_json::to_string(&backup).unwrap()).unwrap();
+    assert_eq!(State::from_json(&path).last_historyvisit_id, 7);
+  }
+
+  #[test]
+  #[ignore]
+  fn state_child() {
+    if let Some(directory) = child_directory() {
+      State::from_json(&directory.join("state.json"));
+    }
+  }
+
+  #[test]
+  fn unreadable_state_without_a_usable_backup_exits() {
+    let fixture = Fixture::new("state-no-backup", "");
+    let path = fixture.directory().join("state.json");
+    fs::write(&path, "{").unwrap();
+    assert_eq!(exit_code_of("tests::state_child", &fixture.directory()), Some(1));
+
+    fs::write(State::backup_path(&path), "not json").unwrap();
+    assert_eq!(exit_code_of("tests::state_child", &fixture.directory()), Some(1));
+  }
+
+  #[test]
+  fn unique_stamp_adds_a_counter_when_the_range_is_taken() {
+    let fixture = Fixture::new("unique-stamp", "duplicate_filenames = \"range\"\nfilename_timestamp_format = \"%Y\"");
+    fixture.add_visits(2);
+    // Two ad hoc exports of the same range get the same timestamp
+    export(&mut fixture.context(), Some(0), false, false);
+    export(&mut fixture.context(), Some(0), false, false);
+
+    let names: Vec<String> = fixture
+      .exports("history_export_")
+      .iter()
+      .map(|file| file.file_name().unwrap().to_string_lossy().into_owned())
+      .collect();
+    let year = Local::now().format("%Y");
+    assert_eq!(
+      names,
+      vec![format!("history_export_{}-0-2-2.json", year), format!("history_export_{}-0-2.json", year)]
+    );
+    let directory = fixture.directory();
+    assert_eq!(unique_stamp(&directory, "alpha", 0, "2020", 0, 2), "2020-0-2");
+    assert_eq!(unique_stamp(&directory, "alpha", 0, &year.to_string(), 0, 2), format!("{}-0-2-3", year));
+  }
+
+  #[test]
+  fn invalid_filename_timestamp_formats_are_rejected() {
+    let fixture = Fixture::new("bad-timestamp-format", "filename_timestamp_format = \"%Y%Q\"");
+    fixture.add_visits(1);
+    assert_eq!(exit_code_of("tests::export_child", &fixture.root), Some(1));
+    assert!(fixture.exports("history_export_").is_empty());
+
+    let fixture = Fixture::new("good-timestamp-format", "filename_timestamp_format = \"%Y%m%dT%H%M%S%.3f\"");
+    fixture.add_visits(1);
+    assert_eq!(exit_code_of("tests::export_child", &fixture.root), Some(0));
+    assert_eq!(fixture.exports("history_export_").len(), 1);
+  }
+
+  #[test]
+  fn compressed_exports_read_back_to_the_entries() {
+    for (algo, extension) in [("gzip", ".json.gz"), ("zstd", ".json.zst")] {
+      let fixture = Fixture::new("compressed", &format!("compression = {{ algo = \"{}\", level = 3 }}", algo));
+      fixture.add_visits(3);
+      export(&mut fixture.context(), None, true, false);
+      let file = fixture.exports("history_export_").remove(0);
+      assert!(file.to_string_lossy().ends_with(extension));
+      assert_eq!(exported_ids(&file), vec![1, 2, 3]);
+    }
+  }
+
+  #[test]
+  fn out_of_range_compression_levels_are_rejected() {
+    for compression in ["gzip\", level = 12", "gzip\", level = -1", "zstd\", level = 0", "zstd\", level = 23"] {
+      let fixture = Fixture::new("bad-level", &format!("compression = {{ algo = \"{} }}", compression));
+      fixture.add_visits(1);
+      assert_eq!(exit_code_of("tests::export_child", &fixture.root), Some(1), "{}", compression);
+      assert!(fixture.exports("history_export_").is_empty());
+    }
+  }
+
+  // Size and write time of 200,000 entries uncompressed and with gzip and
+  // zstd at their default levels. Run with
+  // cargo test --release -- --ignored compression_benchmark --nocapture
+  #[test]
+  #[ignore]
+  fn compression_benchmark() {
+    let fixture = Fixture::new("compression-benchmark", "");
+    fixture.add_visits(200_000);
+    let history = Profile::get_history_range(&fixture.places(), 1, 200_000, &fixture.context().query).unwrap();
+
+    let mut sizes = vec![];
+    for algo in [CompressionAlgo::None, CompressionAlgo::Gzip, CompressionAlgo::Zstd] {
+      let mut options = export_options(true);
+      options.compression = Compression { algo, level: None };
+      let started = Instant::now();
+      let path = write_history_to_file(&history, &fixture.root.join("benchmark.json"), &options);
+      let write_time = started.elapsed();
+      let size = fs::metadata(&path).unwrap().len();
+      println!("{:<5?} {:>10} bytes in {:?}", algo, size, write_time);
+      sizes.push(size);
+    }
+    println!("ratio gzip {:.1}x, zstd {:.1}x", sizes[0] as f64 / sizes[1] as f64, sizes[0] as f64 / sizes[2] as f64);
+    assert!(sizes[1] < sizes[0] && sizes[2] < sizes[0]);
+  }
+
+  // Throughput of get_history over FIREFOX_EXPORTER_BENCH_SIZE visits
+  // (100,000 by default) of as many places as half that, with batched place
+  // lookups and with one lookup per place as before place_batch_size. Peak
+  // memory is the process high water mark, fixture included. Run with
+  // FIREFOX_EXPORTER_BENCH_SIZE=500000 cargo test --release -- --ignored get_history_benchmark --nocapture
+  #[test]
+  #[ignore]
+  fn get_history_benchmark() {
+    let size: usize = std::env::var("FIREFOX_EXPORTER_BENCH_SIZE")
+      .ok()
+      .and_then(|size| size.parse().ok())
+      .unwrap_or(100_000);
+    let places = (size / 2).max(1);
+    let fixture = Fixture::new("get-history-benchmark", "");
+    fixture.add_visits(size);
+    let mut conn = fixture.places();
+    let transaction = conn.transaction().unwrap();
+    for id in 3..=places {
+      transaction
+        .execute(
+          "INSERT INTO moz_places (id, url, title) VALUES (?, ?, 'Page')",
+          params![id as i64, format!("https://example.com/{}", id)],
+        )
+        .unwrap();
+    }
+    transaction
+      .execute("UPDATE moz_historyvisits SET place_id = id % ? + 1", params![places as i64])
+      .unwrap();
+    transaction.commit().unwrap();
+
+    let mut query = fixture.context().query;
+    for batch_size in [query.place_batch_size, 1] {
+      query.place_batch_size = batch_size;
+      let started = Instant::now();
+      let read = Profile::get_history(&conn, 0, &query).unwrap();
+      let elapsed = started.elapsed();
+      assert_eq!(read.read, size);
+      println!(
+        "place_batch_size {:>4}: {} visits in {:?}, {:.0} visits/s",
+        batch_size,
+        size,
+        elapsed,
+        size as f64 / elapsed.as_secs_f64()
+      );
+    }
+    let peak = fs::read_to_string("/proc/self/status")
+      .ok()
+      .and_then(|status| status.lines().find(|line| line.starts_with("VmHWM:")).map(str::to_string));
+    println!("peak memory {}", peak.unwrap_or_else(|| "unknown".to_string()));
+  }
+
+  #[test]
+  #[ignore]
+  fn context_child() {
+    if let Some(root) = child_directory() {
+      Context::from_config(root.join("config.toml"), false, ProfileSource::Config);
+    }
+  }
+
+  #[test]
+  fn profile_directory_errors_exit_instead_of_panicking() {
+    let fixture = Fixture::new("profile-errors", "");
+    let config = fixture.root.join("config.toml");
+    fs::write(&config, "working_directory = \"wd\"\n[profile.alpha]\n").unwrap();
+    assert_eq!(exit_code_of("tests::context_child", &fixture.root), Some(1));
+
+    // Neither profiles.ini nor a *.default directory to pick
+    fs::write(&config, "working_directory = \"wd\"\n[profile.alpha]\nfirefox_path = \"ff\"\nauto_select = true\n").unwrap();
+    assert_eq!(exit_code_of("tests::context_child", &fixture.root), Some(1));
+    fs::write(&config, "working_directory = \"wd\"\n[profile.alpha]\nfirefox_path = \"missing\"\nauto_select = true\n")
+      .unwrap();
+    assert_eq!(exit_code_of("tests::context_child", &fixture.root), Some(1));
+
+    fs::create_dir_all(fixture.root.join("ff/abcd.default-release")).unwrap();
+    assert_eq!(select_profile_directory(&fixture.root.join("ff")), Ok(fixture.root.join("ff/abcd.default-release")));
+  }
+
+  #[test]
+  #[ignore]
+  fn since_file_child() {
+    // Given the --since-file in place of a directory
+    if let Some(file) = child_directory() {
+      last_exported_id(&file);
+    }
+  }
+
+  #[test]
+  fn unreadable_since_files_exit_cleanly() {
+    let fixture = Fixture::new("since-file", "");
+    fixture.add_visits(3);
+    export(&mut fixture.context(), None, true, false);
+    let export = fixture.exports("history_export_").remove(0);
+    assert_eq!(last_exported_id(&export), 3);
+
+    let malformed = fixture.root.join("malformed.json");
+    fs::write(&malformed, "[{\"id\":").unwrap();
+    assert_eq!(exit_code_of("tests::since_file_child", &malformed), Some(1));
+    assert_eq!(exit_code_of("tests::since_file_child", &fixture.root.join("missing.json")), Some(1));
+  }
+
+  #[test]
+  fn checkpoint_pages_exports_and_saves_every_page() {
+    let fixture = Fixture::new("checkpoint-pages", "page_size = 2\ncheckpoint_pages = true");
+    fixture.add_visits(5);
+    export(&mut fixture.context(), None, true, false);
+
+    let files = fixture.exports("history_export_");
+    let pages: Vec<Vec<u64>> = files.iter().map(|file| exported_ids(file)).collect();
+    assert_eq!(pages, vec![vec![1, 2], vec![3, 4], vec![5]]);
+    let state = fixture.state();
+    assert_eq!(state.last_historyvisit_id, 5);
+    assert_eq!(state.exported_total, 5);
+    assert_eq!(state.runs.len(), 3);
+
+    // An ad hoc export doesn't save the cursor, so it isn't paged
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    export(&mut fixture.context(), Some(0), false, false);
+    assert_eq!(exported_ids(fixture.exports("history_export_").last().unwrap()).len(), 5);
+  }
+
+  #[test]
+  fn checkpoint_pages_rejects_whole_run_options() {
+    let fixture = Fixture::new("checkpoint-pages-conflict", "checkpoint_pages = true\nchunk_visits = 10");
+    assert_eq!(exit_code_of("tests::context_child", &fixture.root), Some(1));
+  }
+
+  #[test]
+  fn env_vars_are_expanded_unless_escaped() {
+    std::env::set_var("FIREFOX_EXPORTER_TEST_VAR", "value");
+    assert_eq!(expand_env("a ${FIREFOX_EXPORTER_TEST_VAR} b").unwrap(), "a value b");
+    assert_eq!(expand_env("echo $${FE_COUNT}").unwrap(), "echo ${FE_COUNT}");
+    assert_eq!(expand_env("^x$${2}$").unwrap(), "^x${2}$");
+    assert_eq!(
+      expand_env("$${FIREFOX_EXPORTER_TEST_VAR}=${FIREFOX_EXPORTER_TEST_VAR}").unwrap(),
+      "${FIREFOX_EXPORTER_TEST_VAR}=value"
+    );
+    assert!(expand_env("${FIREFOX_EXPORTER_UNSET_VAR}").is_err());
+  }
+
+  #[test]
+  #[ignore]
+  fn freshness_child() {
+    if let Some(root) = child_directory() {
+      let mut context = Context::from_config(root.join("config.toml"), false, ProfileSource::Config);
+      context.profiles[0].stalled = root.join("stalled").exists();
+      context.check_snapshot_freshness();
+    }
+  }
+
+  #[test]
+  fn strict_freshness_leaves_stalled_profiles_alone() {
+    let fixture = Fixture::new("freshness", "strict_snapshot_freshness = true");
+    let state = State { last_run: u64::MAX / 2, ..Default::default() };
+    fs::write(fixture.directory().join("state.json"), serde_json::to_string(&state).unwrap()).unwrap();
+    // No snapshot was taken since that last run
+    assert_eq!(exit_code_of("tests::freshness_child", &fixture.root), Some(1));
+    fs::write(fixture.root.join("stalled"), "").unwrap();
+    assert_eq!(exit_code_of("tests::freshness_child", &fixture.root), Some(0));
+  }
+
+  #[test]
+  fn profiles_run_in_config_order() {
+    let fixture = Fixture::new("profile-order", "");
+    for name in ["zulu", "mike", "bravo"] {
+      fs::create_dir_all(fixture.root.join("wd/profiles").join(name)).unwrap();
+    }
+    let config = fixture.root.join("config.toml");
+    fs::write(
+      &config,
+      "working_directory = \"wd\"\n\
+       [profile.zulu]\nfirefox_path = \"ff\"\n\
+       [profile.alpha]\nfirefox_path = \"ff\"\n\
+       [profile.mike]\nfirefox_path = \"ff\"\n\
+       [[profiles]]\nname = \"bravo\"\nfirefox_path = \"ff\"\n",
+    )
+    .unwrap();
+    let context = fixture.context();
+    let names: Vec<&str> = context.profiles.iter().map(|profile| profile.name.as_str()).collect();
+    assert_eq!(names, vec!["zulu", "alpha", "mike", "bravo"]);
+
+    let json = fixture.root.join("config.json");
+    fs::write(
+      &json,
+      r#"{"working_directory": "wd", "profile": {"mike": {"firefox_path": "ff"}, "alpha": {"firefox_path": "ff"}}}"#,
+    )
+    .unwrap();
+    let context = Context::from_config(json, false, ProfileSource::Config);
+    let names: Vec<&str> = context.profiles.iter().map(|profile| profile.name.as_str()).collect();
+    assert_eq!(names, vec!["mike", "alpha"]);
+  }
+
+  #[test]
+  #[ignore]
+  fn missing_snapshot_child() {
+    if let Some(root) = child_directory() {
+      let context = Context::from_config(root.join("config.toml"), false, ProfileSource::Config);
+      let opened = context.profiles[0].open().is_ok();
+      let unreadable = health(&context, u64::MAX).unwrap_err().contains("snapshot unreadable");
+      std::process::exit(if !opened && unreadable { 3 } else { 0 });
+    }
+  }
+
+  #[test]
+  fn pooled_connections_never_create_a_missing_snapshot() {
+    // The child's own process, so its short open_timeout_ms applies
+    let fixture = Fixture::new("missing-snapshot", "open_timeout_ms = 100");
+    assert_eq!(exit_code_of("tests::missing_snapshot_child", &fixture.root), Some(3));
+    assert!(!fixture.directory().join("places.sqlite").exists());
+  }
+
+  #[test]
+  fn memory_state_store_keeps_the_last_saved_state() {
+    let mut store = MemoryStateStore::default();
+    assert_eq!(store.load().last_historyvisit_id, 0);
+    store.save(&State { last_historyvisit_id: 4, ..Default::default() });
+    store.save(&State { last_historyvisit_id: 9, ..Default::default() });
+    assert_eq!(store.load().last_historyvisit_id, 9);
+  }
+
+  #[test]
+  fn stateless_runs_keep_the_cursor_in_memory_only() {
+    let fixture = Fixture::new("stateless", "");
+    fixture.add_visits(2);
+    let stateless = || Context::from_config(fixture.root.join("config.toml"), true, ProfileSource::Config);
+    let mut context = stateless();
+    export(&mut context, None, true, false);
+    assert_eq!(context.profiles[0].store.load().last_historyvisit_id, 2);
+    assert!(!fixture.directory().join("state.json").exists());
+
+    // A new process starts over from the first visit
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let mut context = stateless();
+    assert_eq!(context.profiles[0].store.load().last_historyvisit_id, 0);
+    export(&mut context, None, true, false);
+    assert_eq!(fixture.exports("history_export_").len(), 2);
+  }
+
+  #[test]
+  fn missing_sources_go_through_on_profile_error() {
+    let fixture = Fixture::new("missing-source", "on_profile_error = \"skip\"");
+    fs::remove_file(fixture.root.join("ff/places.sqlite")).unwrap();
+    let mut context = fixture.context();
+    export(&mut context, None, true, false);
+    assert!(context.profiles[0].stalled);
+    assert!(fixture.exports("history_export_").is_empty());
+
+    let fixture = Fixture::new("missing-source-fail", "");
+    fs::remove_file(fixture.root.join("ff/places.sqlite")).unwrap();
+    assert_eq!(exit_code_of("tests::export_child", &fixture.root), Some(1));
   }
 }